@@ -1,46 +1,348 @@
 mod restful;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
 
-use crate::backends::DNSSync;
+use crate::backends::{Change, ChangeKind, DNSSync, RecordKey, SyncStatus, UnitReport, Zones};
 use crate::err::*;
+use crate::get_ip::{IpFamily, PrefixHost, ResolvedAddrs, DEFAULT_SOURCE};
+use crate::state::{StateCache, ZoneState};
 use restful::*;
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use serde_yaml::Value as YamlValue;
-use tracing::{debug, error, info};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, info, warn};
+
+// The largest page size accepted when listing dns records.
+const LIST_PER_PAGE: &str = "5000";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Cloudflare {
     #[serde(rename(serialize = "authentication", deserialize = "authentication"))]
     auth: Auth,
+    // For the requests to the Cloudflare API.
+    #[serde(default)]
+    retry: RetryPolicy,
+    #[serde(skip)]
+    rest: Restful,
+    // Seconds between syncs of the zones, overrides the global one.
+    #[serde(default)]
+    check_interval: Option<u64>,
     zones: Vec<Zone>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Zone {
+    // At least one of `id` and `name` is configured, the other one is looked
+    // up once at startup.
+    #[serde(default)]
     id: String,
+    #[serde(default)]
+    name: String,
+    // The ip source followed by records that do not name one.
+    #[serde(default)]
+    ip_source: Option<String>,
+    // Only list the remote records with the name of a managed one, one
+    // request per name, instead of the whole zone.
+    #[serde(default)]
+    filter_listing: bool,
+    // Delete owned remote records which are no longer configured.
+    #[serde(default)]
+    prune: Option<Prune>,
+    // Keeps a TXT record `_dns-syncer.<name>` holding this id next to every
+    // managed name, and leaves names registered to another id alone.
+    #[serde(default)]
+    owner_id: Option<String>,
+    // Seconds between syncs of this zone, overrides the backend's one.
+    #[serde(default)]
+    check_interval: Option<u64>,
     records: Vec<Record>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct Prune {
+    #[serde(flatten)]
+    ownership: Ownership,
+    // A run which would delete more records than this fails without changing
+    // anything.
+    #[serde(default = "default_max_deletions")]
+    max_deletions: usize,
+}
+
+fn default_max_deletions() -> usize {
+    10
+}
+
+// How the records created by this tool are recognized.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "owner", rename_all = "snake_case")]
+enum Ownership {
+    // The comment of the record contains `marker`.
+    Comment { marker: String },
+    // The record has the tag `tag`, e.g. `managed-by:dns-syncer`.
+    Tag { tag: String },
+    // The name of the record is registered to the `owner_id` of the zone.
+    Txt,
+}
+
+impl Ownership {
+    fn owns(&self, record: &Record, registry: Option<&Registry>) -> bool {
+        match self {
+            Self::Comment { marker } => record
+                .comment
+                .as_ref()
+                .is_some_and(|comment| comment.contains(marker.as_str())),
+            Self::Tag { tag } => record.tags.contains(tag),
+            Self::Txt => registry.is_some_and(|registry| registry.owns(record)),
+        }
+    }
+
+    // Makes a configured record carry the ownership mark.
+    fn mark(&self, record: &mut Record) {
+        match self {
+            Self::Comment { marker } => {
+                record.comment = Some(match record.comment.take() {
+                    Some(comment) if comment.contains(marker.as_str()) => comment,
+                    Some(comment) if !comment.is_empty() => format!("{} {}", comment, marker),
+                    _ => marker.clone(),
+                });
+            }
+            Self::Tag { tag } if !record.tags.contains(tag) => record.tags.push(tag.clone()),
+            _ => {}
+        }
+    }
+}
+
+const REGISTRY_PREFIX: &str = "_dns-syncer.";
+const REGISTRY_HERITAGE: &str = "heritage=dns-syncer";
+
+// A registry record, e.g. `heritage=dns-syncer,owner=home,types=A/AAAA`.
+struct Registration {
+    owner: String,
+    // The record types managed at the name.
+    types: Vec<String>,
+    // Of the registry record in the remote records.
+    index: usize,
+}
+
+// The owners of names in a zone, as found in the remote TXT registry.
+struct Registry<'a> {
+    owner_id: &'a str,
+    // By normalized name.
+    names: HashMap<String, Registration>,
+}
+
+impl<'a> Registry<'a> {
+    fn from_remote(owner_id: &'a str, remote_records: &[Record]) -> Self {
+        let names = remote_records
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| Self::is_registry(r))
+            .filter_map(|(index, r)| {
+                let name = normalize_name(&r.name)[REGISTRY_PREFIX.len()..].to_owned();
+                let content = unquote_txt(&r.content);
+                let mut fields = content.split(',');
+                if fields.next() != Some(REGISTRY_HERITAGE) {
+                    return None;
+                }
+
+                let mut owner = None;
+                let mut types = Vec::new();
+                for field in fields {
+                    match field.split_once('=') {
+                        Some(("owner", v)) => owner = Some(v.to_owned()),
+                        Some(("types", v)) => types = v.split('/').map(str::to_owned).collect(),
+                        _ => {}
+                    }
+                }
+
+                let registration = Registration {
+                    owner: owner?,
+                    types,
+                    index,
+                };
+                Some((name, registration))
+            })
+            .collect();
+
+        Self { owner_id, names }
+    }
+
+    fn is_registry(record: &Record) -> bool {
+        record.dns_type == DNSType::TXT && normalize_name(&record.name).starts_with(REGISTRY_PREFIX)
+    }
+
+    fn registry_name(name: &str) -> String {
+        format!("{}{}", REGISTRY_PREFIX, name.trim_end_matches('.'))
+    }
+
+    fn get(&self, name: &str) -> Option<&Registration> {
+        self.names.get(&normalize_name(name))
+    }
+
+    fn owner_of(&self, name: &str) -> Option<&str> {
+        self.get(name).map(|r| r.owner.as_str())
+    }
+
+    fn is_mine(&self, name: &str) -> bool {
+        self.owner_of(name) == Some(self.owner_id)
+    }
+
+    // Whether the record is of a type registered to this owner at its name.
+    fn owns(&self, record: &Record) -> bool {
+        self.get(&record.name).is_some_and(|r| {
+            r.owner == self.owner_id && r.types.contains(&record.dns_type.to_string())
+        })
+    }
+
+    // The TXT record registering the `types` at `name` to this owner.
+    fn record(&self, name: &str, types: &[String]) -> Record {
+        Record {
+            dns_type: DNSType::TXT,
+            name: Self::registry_name(name),
+            content: format!(
+                "{},owner={},types={}",
+                REGISTRY_HERITAGE,
+                self.owner_id,
+                types.join("/")
+            ),
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Debug)]
-enum Action {
-    Patch(String, JsonValue),
-    Post(JsonValue),
+struct Action {
+    kind: ChangeKind,
+    name: String,
+    dns_type: DNSType,
+    // The id of the remote record, empty for a creation.
+    id: String,
+    // The request body, empty for a deletion.
+    body: JsonValue,
+    // The remote record, none for a creation.
+    old: Option<JsonValue>,
 }
 
 impl Action {
-    async fn do_action(&self, zone_id: &str, auth: &Auth) -> Result<()> {
-        match self {
-            Self::Post(data) => Self::post(zone_id, auth, data).await?,
-            Self::Patch(id, data) => Self::patch(zone_id, auth, id, data).await?,
+    async fn do_action(&self, zone_id: &str, rest: &Restful) -> Result<()> {
+        match self.kind {
+            ChangeKind::Create => Self::post(zone_id, rest, &self.body).await?,
+            ChangeKind::Update => Self::patch(zone_id, rest, &self.id, &self.body).await?,
+            ChangeKind::Delete => Self::remove(zone_id, rest, &self.id).await?,
         }
 
         Ok(())
     }
 
+    fn change(&self, zone: &str) -> Change {
+        let new = match (self.kind, &self.old, &self.body) {
+            (ChangeKind::Delete, _, _) => None,
+            // A patch only holds the changed fields.
+            (ChangeKind::Update, Some(JsonValue::Object(old)), JsonValue::Object(patch)) => {
+                let mut new = old.clone();
+                new.extend(patch.clone());
+                Some(JsonValue::Object(new))
+            }
+            (_, _, body) => Some(body.clone()),
+        };
+
+        Change {
+            backend: "cloudflare".to_string(),
+            zone: zone.to_owned(),
+            action: self.kind,
+            record: RecordKey {
+                name: self.name.clone(),
+                dns_type: self.dns_type.to_string(),
+            },
+            old: self.old.clone(),
+            new,
+        }
+    }
+
+    // Returns a patch turning `remote` into `local` with `content`, if they
+    // differ in anything managed.
+    fn from_diff(local: &Record, content: &str, remote: &Record) -> Result<Option<Self>> {
+        let mut patch: Record = Default::default();
+        let mut need_update = false;
+
+        if local.dns_type != remote.dns_type {
+            debug!(
+                "dns type changed from {:?} to {:?}",
+                remote.dns_type, local.dns_type
+            );
+            need_update = true;
+        }
+
+        if !local.same_value(content, remote) {
+            match &local.data {
+                Some(data) => debug!("data changed from {:?} to {:?}", remote.data, data),
+                None => debug!("content change from {} to {}", remote.content, content),
+            }
+            need_update = true;
+        }
+
+        if local.priority.is_some() && local.priority != remote.priority {
+            debug!(
+                "priority changed from {:?} to {:?}",
+                remote.priority, local.priority
+            );
+            need_update = true;
+        }
+
+        // ttl == 1 means auto
+        match (&local.ttl, &remote.ttl) {
+            (Some(local), Some(remote)) if local != remote => {
+                debug!("ttl changed from {:?} to {:?}", remote, local);
+                need_update = true;
+                patch.ttl = Some(local.to_owned());
+            }
+            (_, None) => {
+                error!("[BUG] remote ttl is NONE");
+            }
+            _ => {}
+        };
+
+        match (&local.proxied, &remote.proxied) {
+            (Some(local), Some(remote)) if local != remote => {
+                debug!("proxied changed from {:?} to {:?}", remote, local);
+                need_update = true;
+                patch.proxied = Some(local.to_owned());
+            }
+            (_, None) => {
+                error!("[BUG] remote proxied is NONE");
+            }
+            _ => {}
+        }
+
+        if !local.tags.is_empty() && !same_tags(&local.tags, &remote.tags) {
+            debug!("tags changed from {:?} to {:?}", remote.tags, local.tags);
+            need_update = true;
+            patch.tags = local.tags.clone();
+        }
+
+        match (&local.comment, &remote.comment) {
+            (Some(local), remote) if remote.as_ref() != Some(local) => {
+                debug!("comment changed from {:?} to {:?}", remote, local);
+                need_update = true;
+                patch.comment = Some(local.clone());
+            }
+            _ => {}
+        }
+
+        if !need_update {
+            return Ok(None);
+        }
+
+        patch.priority = local.priority;
+        patch.data = local.data.clone();
+        let action = Action::from_patch(&mut patch, remote, &local.name, content, &local.dns_type)?;
+        Ok(Some(action))
+    }
+
     fn from_patch(
         patch: &mut Record,
         remote: &Record,
@@ -73,16 +375,22 @@ impl Action {
             info!("\n{}", table);
         }
 
-        let action = Action::Patch(remote.id.clone(), serde_json::from_str(&patch_json)?);
+        let action = Action {
+            kind: ChangeKind::Update,
+            name: name.to_owned(),
+            dns_type: dns_type.to_owned(),
+            id: remote.id.clone(),
+            body: serde_json::from_str(&patch_json)?,
+            old: Some(serde_json::from_str(&old_val_json)?),
+        };
 
         Ok(action)
     }
 
-    fn new(record: &Record, v4addr: &str) -> Result<Self> {
+    fn new(record: &Record, content: &str) -> Result<Self> {
         let mut local = record.clone();
-        if local.content.is_empty() {
-            local.content = v4addr.to_owned();
-        }
+        let auto_content = local.content.is_empty() && local.data.is_none();
+        local.content = content.to_owned();
         if local.ttl.is_none() {
             local.ttl = Some(1);
         }
@@ -98,10 +406,10 @@ impl Action {
                 settings::{object::Rows, Alignment, Modify},
             };
 
-            let note = if local.content.is_empty() {
-                ""
-            } else {
+            let note = if auto_content {
                 "IP is automatically obtained from Cloudfalre"
+            } else {
+                ""
             };
 
             let mut builder = Builder::default();
@@ -112,178 +420,614 @@ impl Action {
             info!("\n{}", table);
         }
 
-        let action = Action::Post(serde_json::from_str(&post_body)?);
+        let action = Action {
+            kind: ChangeKind::Create,
+            name: local.name.clone(),
+            dns_type: local.dns_type.clone(),
+            id: String::new(),
+            body: serde_json::from_str(&post_body)?,
+            old: None,
+        };
 
         Ok(action)
     }
 
-    async fn patch(zone_id: &str, auth: &Auth, record_id: &str, data: &JsonValue) -> Result<()> {
+    async fn patch(zone_id: &str, rest: &Restful, record_id: &str, data: &JsonValue) -> Result<()> {
         let url = format!(
             "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
             zone_id, record_id,
         );
 
-        let (k, v) = auth.http_header();
-        let headers: Vec<(&str, &str)> = vec![(&k, &v)];
-
-        Restful::patch(url.as_str(), Some(headers), data).await?;
+        rest.patch(url.as_str(), data).await?;
 
         Ok(())
     }
 
-    async fn post(zone_id: &str, auth: &Auth, data: &JsonValue) -> Result<()> {
+    fn delete(remote: &Record) -> Result<Self> {
+        let old_val_json = serde_json::to_string_pretty(remote)?;
+
+        {
+            use tabled::{
+                builder::Builder,
+                settings::{object::Rows, Alignment, Modify},
+            };
+
+            let mut builder = Builder::default();
+            builder.push_record(vec!["Delete DNS Record"]);
+            builder.push_record(vec![&old_val_json]);
+            let mut table = builder.build();
+            table.with(Modify::new(Rows::first()).with(Alignment::center()));
+            info!("\n{}", table);
+        }
+
+        Ok(Action {
+            kind: ChangeKind::Delete,
+            name: remote.name.clone(),
+            dns_type: remote.dns_type.clone(),
+            id: remote.id.clone(),
+            body: JsonValue::Null,
+            old: Some(serde_json::from_str(&old_val_json)?),
+        })
+    }
+
+    async fn post(zone_id: &str, rest: &Restful, data: &JsonValue) -> Result<()> {
         let url = format!(
             "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
             zone_id,
         );
 
-        let (k, v) = auth.http_header();
-        let headers: Vec<(&str, &str)> = vec![(&k, &v)];
+        rest.post(url.as_str(), data).await?;
+
+        Ok(())
+    }
+
+    async fn remove(zone_id: &str, rest: &Restful, record_id: &str) -> Result<()> {
+        let url = format!(
+            "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
+            zone_id, record_id,
+        );
 
-        Restful::post(url.as_str(), Some(headers), data).await?;
+        rest.delete(url.as_str()).await?;
 
         Ok(())
     }
 }
 
 impl Zone {
-    async fn list_recordds(&self, auth: &Auth) -> Result<Vec<Record>> {
+    async fn list_recordds(&self, rest: &Restful) -> Result<Vec<Record>> {
+        // Pruning needs to see the records which are no longer configured.
+        if !self.filter_listing || self.prune.is_some() {
+            return self.list_pages(rest, &[]).await;
+        }
+
+        // Records of other types are listed too, they may be in the way of a
+        // managed one.
+        let mut names: Vec<String> = self
+            .records
+            .iter()
+            .map(|r| normalize_name(&r.name))
+            .collect();
+        if self.owner_id.is_some() {
+            let registry_names: Vec<String> =
+                names.iter().map(|n| Registry::registry_name(n)).collect();
+            names.extend(registry_names);
+        }
+        names.sort();
+        names.dedup();
+
+        let mut dns_records = Vec::new();
+        for name in names.iter() {
+            dns_records.extend(self.list_pages(rest, &[("name", name)]).await?);
+        }
+        Ok(dns_records)
+    }
+
+    // Follows `result_info` until every page of the listing has been read.
+    async fn list_pages(&self, rest: &Restful, filter: &[(&str, &str)]) -> Result<Vec<Record>> {
         let url = format!(
             "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
             self.id,
         );
 
-        let (k, v) = auth.http_header();
-        let headers: Vec<(&str, &str)> = vec![(&k, &v)];
-        let resp = Restful::get(&url, Some(headers)).await?;
+        let mut dns_records = Vec::new();
+        let mut page = 1;
+        loop {
+            let url = reqwest::Url::parse_with_params(
+                &url,
+                filter.iter().copied().chain([
+                    ("page", page.to_string().as_str()),
+                    ("per_page", LIST_PER_PAGE),
+                ]),
+            )
+            .map_err(|e| AppErr::config(format!("invalid dns records url {}: {}", url, e)))?;
+
+            let resp = rest.get(url.as_str()).await?;
 
-        let mut h: HashMap<String, serde_json::Value> = serde_json::from_value(resp)?;
-        let result = h.remove("result").ok_or(AppErr {
-            msg: String::from(
+            let mut h: HashMap<String, serde_json::Value> = serde_json::from_value(resp)?;
+            let result = h.remove("result").ok_or(AppErr::provider(String::from(
                 "list dns records, and there is no 'result' filed in the returned json",
-            ),
-        })?;
+            )))?;
+
+            let records: Vec<Record> = serde_json::from_value(result)?;
+            let fetched = records.len();
+            dns_records.extend(records);
+
+            // Without `result_info` the listing is assumed to fit in one page.
+            let total_pages = h
+                .get("result_info")
+                .and_then(|info| info["total_pages"].as_u64())
+                .unwrap_or(1);
+            debug!(
+                "listed page {}/{} of zone {}, {} records",
+                page, total_pages, self.id, fetched
+            );
+
+            if page >= total_pages || fetched == 0 {
+                break;
+            }
+            page += 1;
+        }
 
-        let dns_records: Vec<Record> = serde_json::from_value(result)?;
         Ok(dns_records)
     }
 
-    async fn get_actions_by_diff(
+    fn ip_source_of<'a>(&'a self, record: &'a Record) -> &'a str {
+        record
+            .ip_source
+            .as_deref()
+            .or(self.ip_source.as_deref())
+            .unwrap_or(DEFAULT_SOURCE)
+    }
+
+    fn wanted_ips(&self) -> Vec<(String, IpFamily)> {
+        self.records
+            .iter()
+            .filter(|r| r.content.is_empty())
+            .filter_map(|r| Some((self.ip_source_of(r).to_owned(), r.dns_type.ip_family()?)))
+            .collect()
+    }
+
+    // The addresses this zone follows, as recorded in the state cache.
+    fn followed_addrs(&self, addrs: &ResolvedAddrs) -> BTreeMap<String, String> {
+        self.wanted_ips()
+            .into_iter()
+            .filter_map(|(source, family)| {
+                let ip = addrs.get(&source, family)?;
+                Some((format!("{}/{}", source, family), ip.to_string()))
+            })
+            .collect()
+    }
+
+    // The configured records grouped by name and type, in config order.
+    fn record_sets(&self) -> Vec<(&str, &DNSType, Vec<&Record>)> {
+        let mut sets: Vec<(&str, &DNSType, Vec<&Record>)> = Vec::new();
+        for record in self.records.iter() {
+            let set = sets
+                .iter_mut()
+                .find(|(name, t, _)| same_name(name, &record.name) && **t == record.dns_type);
+            match set {
+                Some((_, _, records)) => records.push(record),
+                None => sets.push((&record.name, &record.dns_type, vec![record])),
+            }
+        }
+        sets
+    }
+
+    fn declares(&self, name: &str, dns_type: &DNSType) -> bool {
+        self.records
+            .iter()
+            .any(|r| same_name(&r.name, name) && r.dns_type == *dns_type)
+    }
+
+    // Records of the same name and type form a set, e.g. the A records of a
+    // round-robin name, which is reconciled as a whole. Remote records which
+    // already have a wanted value are kept, the others are rewritten to the
+    // missing values, and missing ones are created. Remote records left over
+    // are only deleted by pruning.
+    fn get_actions_by_diff(
         &self,
-        v4addr: &str,
+        addrs: &ResolvedAddrs,
         remote_records: Vec<Record>,
-    ) -> Result<Vec<Action>> {
-        let mut actions: Vec<Action> = Vec::new();
+    ) -> Result<Diff> {
+        let mut skipped: Vec<AppErr> = Vec::new();
+        let mut patches: Vec<Action> = Vec::new();
+        let mut posts: Vec<Action> = Vec::new();
+        let mut claimed = vec![false; remote_records.len()];
 
-        for local in self.records.iter() {
-            let mut processed = false;
+        let registry = self
+            .owner_id
+            .as_deref()
+            .map(|owner_id| Registry::from_remote(owner_id, &remote_records));
+        // The registry is maintained below, apart from the records it covers.
+        if registry.is_some() {
+            for (i, remote) in remote_records.iter().enumerate() {
+                claimed[i] = Registry::is_registry(remote);
+            }
+        }
+        // Names whose records have been reconciled, and so are owned now.
+        let mut managed: Vec<(&str, Vec<String>)> = Vec::new();
+        // Remote records of a managed name and type which are not configured.
+        let mut surplus: Vec<usize> = Vec::new();
 
-            for remote in remote_records.iter() {
-                let mut patch: Record = Default::default();
-                let mut need_update = false;
+        for (name, dns_type, locals) in self.record_sets() {
+            let candidates = |replaced: bool| -> Vec<usize> {
+                remote_records
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, r)| same_name(&r.name, name))
+                    .filter(|(_, r)| match replaced {
+                        false => r.dns_type == *dns_type,
+                        true => {
+                            dns_type.conflicts_with(&r.dns_type)
+                                && !self.declares(name, &r.dns_type)
+                        }
+                    })
+                    .map(|(i, _)| i)
+                    .collect()
+            };
+            // Remote records of a type which cannot coexist with this set,
+            // e.g. an A record where a CNAME is wanted, are taken over.
+            let same_type = candidates(false);
+            let replaced = candidates(true);
 
-                if local.name != remote.name {
+            if let Some(owner) = registry.as_ref().and_then(|r| r.owner_of(name)) {
+                if Some(owner) != self.owner_id.as_deref() {
+                    warn!("skip {} records of {}: owned by {}", dns_type, name, owner);
+                    for i in same_type.iter().chain(replaced.iter()) {
+                        claimed[*i] = true;
+                    }
                     continue;
                 }
+            }
 
-                if local.dns_type != remote.dns_type {
-                    debug!(
-                        "dns type changed from {:?} to {:?}",
-                        remote.dns_type, local.dns_type
-                    );
-                    need_update = true;
+            let wanted: Result<Vec<(&Record, String)>> = locals
+                .into_iter()
+                .map(|local| {
+                    let content = local.resolve_content(self.ip_source_of(local), addrs)?;
+                    Ok((local, content))
+                })
+                .collect();
+            let wanted = match wanted {
+                Ok(wanted) => wanted,
+                Err(e) => {
+                    error!("skip {} records of {}: {}", dns_type, name, e);
+                    // Keep the remote records of a skipped set from pruning.
+                    for i in same_type.iter().chain(replaced.iter()) {
+                        claimed[*i] = true;
+                    }
+                    skipped.push(e);
+                    continue;
                 }
+            };
 
-                if (local.content.is_empty() && v4addr != remote.content)
-                    || (!local.content.is_empty() && local.content != remote.content)
-                {
-                    debug!(
-                        "content change from {} to {}",
-                        remote.content, local.content
-                    );
-                    need_update = true;
+            let mut unmatched = Vec::new();
+            for (local, content) in wanted.into_iter() {
+                let exact = same_type
+                    .iter()
+                    .copied()
+                    .find(|&i| !claimed[i] && local.same_value(&content, &remote_records[i]));
+                match exact {
+                    Some(i) => {
+                        claimed[i] = true;
+                        patches.extend(Action::from_diff(local, &content, &remote_records[i])?);
+                    }
+                    None => unmatched.push((local, content)),
                 }
+            }
 
-                // ttl == 1 means auto
-                match (&local.ttl, &remote.ttl) {
-                    (Some(local), Some(remote)) if local != remote => {
-                        debug!("ttl changed from {:?} to {:?}", remote, local);
-                        need_update = true;
-                        patch.ttl = Some(local.to_owned());
-                    }
-                    (_, None) => {
-                        error!("[BUG] remote ttl is NONE");
-                    }
-                    _ => {}
-                };
+            let spare: Vec<usize> = same_type
+                .iter()
+                .chain(replaced.iter())
+                .copied()
+                .filter(|&i| !claimed[i])
+                .collect();
+            let mut spare = spare.into_iter();
 
-                match (&local.proxied, &remote.proxied) {
-                    (Some(local), Some(remote)) if local != remote => {
-                        debug!("proxied changed from {:?} to {:?}", remote, local);
-                        need_update = true;
-                        patch.proxied = Some(local.to_owned());
-                    }
-                    (_, None) => {
-                        error!("[BUG] remote proxied is NONE");
+            for (local, content) in unmatched.into_iter() {
+                match spare.next() {
+                    Some(i) => {
+                        claimed[i] = true;
+                        patches.extend(Action::from_diff(local, &content, &remote_records[i])?);
                     }
-                    _ => {}
+                    None => posts.push(Action::new(local, &content)?),
                 }
+            }
+
+            // Records beyond the size of the set, or in the way of its type, are
+            // only deleted by pruning, as they may have been added by hand.
+            let spare: Vec<usize> = spare.collect();
+
+            // Registering a type claims every record of it at the name, so a
+            // type is only added while no record of it is left unclaimed.
+            // Otherwise records this tool did not create would be pruned.
+            let registered = registry
+                .as_ref()
+                .and_then(|r| r.get(name))
+                .is_some_and(|r| r.types.contains(&dns_type.to_string()));
+            let foreign = spare
+                .iter()
+                .filter(|&&i| remote_records[i].dns_type == *dns_type)
+                .count();
+            surplus.extend(spare);
+            if registry.is_some() && !registered && foreign > 0 {
+                warn!(
+                    "do not register {} at {}: {} records of it were not created by this tool",
+                    dns_type, name, foreign
+                );
+                continue;
+            }
 
-                match (&local.comment, &remote.comment) {
-                    (Some(local), Some(remote)) if local != remote => {
-                        debug!("comment changed from {:?} to {:?}", remote, local);
-                        need_update = true;
-                        patch.comment = Some(local.clone());
+            match managed.iter_mut().find(|(n, _)| same_name(n, name)) {
+                Some((_, types)) => types.push(dns_type.to_string()),
+                None => managed.push((name, vec![dns_type.to_string()])),
+            }
+        }
+
+        if let Some(registry) = &registry {
+            for (name, types) in managed.iter_mut() {
+                types.sort();
+                let record = registry.record(name, types);
+                match registry.get(name) {
+                    Some(registration) => {
+                        let remote = &remote_records[registration.index];
+                        patches.extend(Action::from_diff(&record, &record.content, remote)?);
                     }
-                    _ => {}
+                    None => posts.push(Action::new(&record, &record.content)?),
                 }
+            }
+        }
 
-                processed = true;
-                if need_update {
-                    let content = if local.content.is_empty() {
-                        v4addr
-                    } else {
-                        &local.content
-                    };
-
-                    let action = Action::from_patch(
-                        &mut patch,
-                        remote,
-                        &local.name,
-                        content,
-                        &local.dns_type,
-                    )?;
-                    actions.push(action);
+        let deletes = self.prune_actions(&remote_records, &mut claimed, registry.as_ref())?;
+
+        for i in surplus.into_iter().filter(|&i| !claimed[i]) {
+            let remote = &remote_records[i];
+            warn!(
+                "keep {} record {} ({}): not configured and not pruned",
+                remote.dns_type, remote.name, remote.content
+            );
+        }
+
+        // Deletions first, as pruned records may be in the way of a type
+        // change.
+        let actions: Vec<Action> = deletes.into_iter().chain(patches).chain(posts).collect();
+        self.check_deletions(&actions)?;
+        Ok(Diff { actions, skipped })
+    }
+
+    // The only source of deletions: remote records which are not claimed by a
+    // configured record and carry the ownership mark, and registrations of
+    // names without configured records. Nothing is deleted without `prune`.
+    fn prune_actions(
+        &self,
+        remote_records: &[Record],
+        claimed: &mut [bool],
+        registry: Option<&Registry>,
+    ) -> Result<Vec<Action>> {
+        let Some(prune) = &self.prune else {
+            return Ok(Vec::new());
+        };
+
+        let mut deletes = Vec::new();
+        for (i, remote) in remote_records.iter().enumerate() {
+            if !claimed[i] && prune.ownership.owns(remote, registry) {
+                claimed[i] = true;
+                deletes.push(Action::delete(remote)?);
+            }
+        }
+
+        if let Some(registry) = registry {
+            for remote in remote_records.iter().filter(|r| Registry::is_registry(r)) {
+                let name = &normalize_name(&remote.name)[REGISTRY_PREFIX.len()..];
+                if registry.is_mine(name) && !self.records.iter().any(|r| same_name(&r.name, name))
+                {
+                    deletes.push(Action::delete(remote)?);
                 }
             }
+        }
 
-            if processed {
+        Ok(deletes)
+    }
+
+    // Every deletion counts against `max_deletions`, a sync exceeding it fails
+    // without changing anything.
+    fn check_deletions(&self, actions: &[Action]) -> Result<()> {
+        let deletes = actions
+            .iter()
+            .filter(|a| a.kind == ChangeKind::Delete)
+            .count();
+        let max_deletions = self.prune.as_ref().map_or(0, |prune| prune.max_deletions);
+
+        if deletes > max_deletions {
+            return Err(AppErr::provider(format!(
+                "zone {}: {} deletions exceed max_deletions {}, abort",
+                self.name, deletes, max_deletions
+            )));
+        }
+        Ok(())
+    }
+
+    // Fills in whichever of the zone id and name is not configured, and checks
+    // that all records belong to the zone.
+    async fn resolve(&mut self, rest: &Restful) -> Result<()> {
+        if self.id.is_empty() {
+            let url = reqwest::Url::parse_with_params(
+                "https://api.cloudflare.com/client/v4/zones",
+                [("name", self.name.trim_end_matches('.').to_ascii_lowercase())],
+            )
+            .map_err(|e| AppErr::config(format!("invalid zone name {}: {}", self.name, e)))?;
+            let resp = rest.get(url.as_str()).await?;
+
+            self.id = resp["result"][0]["id"]
+                .as_str()
+                .ok_or(AppErr::config(format!("cannot find zone {}", self.name)))?
+                .to_owned();
+            info!("zone {} has id {}", self.name, self.id);
+        } else {
+            let url = format!("https://api.cloudflare.com/client/v4/zones/{}", self.id);
+            let resp = rest.get(&url).await?;
+
+            let name = resp["result"]["name"]
+                .as_str()
+                .ok_or(AppErr::provider(format!(
+                    "cannot find the name of zone {}",
+                    self.id
+                )))?;
+            if !self.name.is_empty() && !same_name(&self.name, name) {
+                return Err(AppErr::config(format!(
+                    "zone {} is named {}, not {}",
+                    self.id, name, self.name
+                )));
+            }
+            self.name = name.to_owned();
+        }
+
+        let suffix = format!(".{}", self.name.trim_end_matches('.')).to_ascii_lowercase();
+        for record in self.records.iter() {
+            let name = record.name.trim_end_matches('.').to_ascii_lowercase();
+            if !same_name(&record.name, &self.name) && !name.ends_with(&suffix) {
+                return Err(AppErr::config(format!(
+                    "record {} is not in zone {}",
+                    record.name, self.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the number of applied actions, or the first error if any action
+    // failed. A rejected record does not stop the others, but once the
+    // credentials are refused or the rate limit is still exhausted after
+    // retrying, the remaining actions are given up.
+    async fn do_actions(&self, rest: &Restful, actions: Vec<Action>) -> Result<usize> {
+        let total = actions.len();
+        let mut failed = 0;
+        let mut first_err = None;
+        for (done, action) in actions.into_iter().enumerate() {
+            let Err(e) = action.do_action(&self.id, rest).await else {
                 continue;
+            };
+            error!("do this action failed: {}\n{:?}", e, action);
+            failed += 1;
+
+            let give_up = matches!(
+                e.api_kind(),
+                Some(ApiErrorKind::Auth | ApiErrorKind::RateLimited)
+            );
+            first_err.get_or_insert(e);
+
+            if give_up {
+                let skipped = total - done - 1;
+                if skipped > 0 {
+                    error!("zone {}: skip the remaining {} actions", self.name, skipped);
+                }
+                failed += skipped;
+                break;
             }
+        }
 
-            actions.push(Action::new(local, v4addr)?);
+        match first_err {
+            None => Ok(total),
+            Some(e) => Err(e.context(&format!("{} of {} changes failed", failed, total))),
         }
+    }
+
+    async fn plan(&self, rest: &Restful, addrs: &ResolvedAddrs) -> Result<Vec<Change>> {
+        let remote_records = self.list_recordds(rest).await?;
+        self.planned_changes(addrs, remote_records)
+    }
 
-        Ok(actions)
+    // A plan leaving out record sets is incomplete, so it fails like a sync
+    // would, instead of reporting no pending changes.
+    fn planned_changes(
+        &self,
+        addrs: &ResolvedAddrs,
+        remote_records: Vec<Record>,
+    ) -> Result<Vec<Change>> {
+        let diff = self.get_actions_by_diff(addrs, remote_records)?;
+        info!("zone {}: {} changes planned", self.name, diff.actions.len());
+        if let Some(e) = diff.skipped.into_iter().next() {
+            return Err(e);
+        }
+        Ok(diff.actions.iter().map(|a| a.change(&self.name)).collect())
     }
 
-    async fn do_actions(&self, auth: &Auth, actions: Vec<Action>) -> Result<()> {
-        for action in actions.into_iter() {
-            if let Err(e) = action.do_action(&self.id, auth).await {
-                error!("do this action failed: {:?}\n{:?}", e, action);
+    // The config of this zone as JSON with sorted keys, for the state cache.
+    // The record fields which are not sent to the API are added back.
+    fn canonical_config(&self) -> String {
+        let mut config = serde_json::to_value(self).expect("the config serializes to JSON");
+        if let Some(records) = config["records"].as_array_mut() {
+            for (value, record) in records.iter_mut().zip(self.records.iter()) {
+                value["ip_source"] = serde_json::json!(record.ip_source);
+                value["ipv6_prefix"] = serde_json::json!(record.ipv6_prefix);
             }
         }
-        Ok(())
+        config.to_string()
     }
 
-    async fn sync(&self, auth: &Auth, v4addr: &str) -> Result<()> {
-        let remote_records = self.list_recordds(auth).await?;
-        let actions = self.get_actions_by_diff(v4addr, remote_records).await?;
-        self.do_actions(auth, actions).await?;
-        Ok(())
+    async fn sync(
+        &self,
+        rest: &Restful,
+        addrs: &ResolvedAddrs,
+        state: &StateCache,
+    ) -> Result<SyncStatus> {
+        let key = format!("cloudflare/{}", self.id);
+        let current = ZoneState::new(self.followed_addrs(addrs), &self.canonical_config());
+        if state.is_fresh(&key, &current) {
+            debug!("zone {} is unchanged since the last sync, skip", self.id);
+            return Ok(SyncStatus::Unchanged);
+        }
+
+        let result = async {
+            let remote_records = self.list_recordds(rest).await?;
+            let diff = self.get_actions_by_diff(addrs, remote_records)?;
+            let applied = self.do_actions(rest, diff.actions).await?;
+            // The other sets are synced, but the zone is not up to date.
+            match diff.skipped.into_iter().next() {
+                Some(e) => Err(e),
+                None => Ok(applied),
+            }
+        }
+        .await;
+
+        match result {
+            Ok(applied) => {
+                state.record_success(&key, current);
+                Ok(SyncStatus::Synced { changes: applied })
+            }
+            Err(e) => {
+                state.record_failure(&key);
+                Err(e)
+            }
+        }
     }
 }
 
+// The actions reconciling a zone, and why record sets were left out of it,
+// e.g. for want of a public address.
+struct Diff {
+    actions: Vec<Action>,
+    skipped: Vec<AppErr>,
+}
+
+fn same_tags(a: &[String], b: &[String]) -> bool {
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    a.sort();
+    b.sort();
+    a == b
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// DNS names compare case insensitively, with or without the root dot.
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 struct Record {
     #[serde(skip_serializing, default)]
@@ -294,9 +1038,19 @@ struct Record {
 
     name: String,
 
-    #[serde(default)]
+    // Records with `data` have their content generated by Cloudflare.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     content: String,
 
+    // MX only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<u16>,
+
+    // The structured content of SRV, CAA, HTTPS and SVCB records, e.g.
+    // `{priority: 10, weight: 5, port: 5060, target: sip.example.com}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<JsonValue>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     proxied: Option<bool>,
 
@@ -305,32 +1059,226 @@ struct Record {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     comment: Option<String>,
+
+    // Cloudflare tags, `name:value`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+
+    // The ip source an empty content follows, overrides the zone's one.
+    #[serde(skip_serializing, default)]
+    ip_source: Option<String>,
+
+    // AAAA only, publish a host inside the prefix of the followed address.
+    #[serde(skip_serializing, default)]
+    ipv6_prefix: Option<PrefixHost>,
+}
+
+impl Record {
+    fn validate(&self) -> Result<()> {
+        match &self.dns_type {
+            DNSType::Uninit | DNSType::Other => {
+                return Err(AppErr::config(format!(
+                    "record {}: unsupported record type",
+                    self.name
+                )));
+            }
+            t if t.has_data() && self.data.is_none() => {
+                return Err(AppErr::config(format!(
+                    "record {}: {} record needs 'data'",
+                    self.name, t
+                )));
+            }
+            t if !t.has_data() && self.data.is_some() => {
+                return Err(AppErr::config(format!(
+                    "record {}: {} record takes no 'data'",
+                    self.name, t
+                )));
+            }
+            DNSType::MX if self.priority.is_none() => {
+                return Err(AppErr::config(format!(
+                    "record {}: MX record needs 'priority'",
+                    self.name
+                )));
+            }
+            t if !t.has_data() && t.ip_family().is_none() && self.content.is_empty() => {
+                return Err(AppErr::config(format!(
+                    "record {}: {} record needs 'content'",
+                    self.name, t
+                )));
+            }
+            _ => {}
+        }
+
+        if let Some(prefix) = &self.ipv6_prefix {
+            if self.dns_type != DNSType::AAAA || !self.content.is_empty() {
+                return Err(AppErr::config(format!(
+                    "record {}: ipv6_prefix needs an AAAA record without content",
+                    self.name
+                )));
+            }
+
+            prefix
+                .validate()
+                .map_err(|e| AppErr::config(format!("record {}: {}", self.name, e)))?;
+        }
+
+        Ok(())
+    }
+
+    // Whether `remote` already has the value this record has with `content`.
+    fn same_value(&self, content: &str, remote: &Record) -> bool {
+        match &self.data {
+            Some(data) => remote.data.as_ref().is_some_and(|r| same_data(data, r)),
+            None => self.dns_type.normalize(content) == self.dns_type.normalize(&remote.content),
+        }
+    }
+
+    // An empty `content` means the record follows the public address of the
+    // family matching its type, as reported by the ip source `source`.
+    fn resolve_content(&self, source: &str, addrs: &ResolvedAddrs) -> Result<String> {
+        if !self.content.is_empty() || self.data.is_some() {
+            return Ok(self.content.clone());
+        }
+
+        let family = self.dns_type.ip_family().ok_or(AppErr::config(format!(
+            "record {} of type {} has no content",
+            self.name, self.dns_type
+        )))?;
+
+        let ip = addrs
+            .get(source, family)
+            .ok_or(AppErr::ip_discovery(format!(
+                "no public {} address from ip source '{}' for record {}",
+                family, source, self.name
+            )))?;
+
+        match (ip, &self.ipv6_prefix) {
+            (IpAddr::V6(ip), Some(prefix)) => Ok(prefix.apply(ip)?.to_string()),
+            _ => Ok(ip.to_string()),
+        }
+    }
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
 enum DNSType {
+    #[default]
     Uninit,
     A,
     AAAA,
     CNAME,
+    MX,
+    TXT,
+    SRV,
+    CAA,
+    NS,
+    HTTPS,
+    SVCB,
+    PTR,
+    // Remote records of a type not managed by this tool.
+    #[serde(other)]
+    Other,
 }
 
-impl Default for DNSType {
-    fn default() -> Self {
-        Self::Uninit
+impl DNSType {
+    fn ip_family(&self) -> Option<IpFamily> {
+        match self {
+            Self::A => Some(IpFamily::V4),
+            Self::AAAA => Some(IpFamily::V6),
+            _ => None,
+        }
+    }
+
+    // A CNAME cannot coexist with address records of the same name.
+    fn conflicts_with(&self, other: &Self) -> bool {
+        matches!(
+            (self, other),
+            (Self::CNAME, Self::A | Self::AAAA) | (Self::A | Self::AAAA, Self::CNAME)
+        )
     }
-}
 
-impl ToString for DNSType {
-    fn to_string(&self) -> String {
+    // Types whose content is given as structured `data`.
+    fn has_data(&self) -> bool {
+        matches!(self, Self::SRV | Self::CAA | Self::HTTPS | Self::SVCB)
+    }
+
+    // Brings content to the form used for comparison, as the same record may
+    // be written differently in the config and by Cloudflare.
+    fn normalize(&self, content: &str) -> String {
+        let content = content.trim();
         match self {
+            Self::A | Self::AAAA => match content.parse::<IpAddr>() {
+                Ok(ip) => ip.to_string(),
+                Err(_) => content.to_owned(),
+            },
+            Self::CNAME | Self::MX | Self::NS | Self::PTR => {
+                content.trim_end_matches('.').to_ascii_lowercase()
+            }
+            Self::TXT => unquote_txt(content),
+            _ => content.to_owned(),
+        }
+    }
+}
+
+// TXT content may be a sequence of quoted character strings, which are joined
+// into one, e.g. `"v=spf1 " "-all"` is `v=spf1 -all`.
+fn unquote_txt(content: &str) -> String {
+    if !content.starts_with('"') || !content.ends_with('"') || content.len() < 2 {
+        return content.to_owned();
+    }
+
+    let mut text = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => text.extend(chars.next()),
+            c if quoted => text.push(c),
+            c if c.is_whitespace() => {}
+            // Not a sequence of quoted strings, keep it as it is.
+            _ => return content.to_owned(),
+        }
+    }
+    text
+}
+
+// Whether every field of the configured `local` data has the same value in
+// the `remote` one. Cloudflare may return more fields than were configured.
+fn same_data(local: &JsonValue, remote: &JsonValue) -> bool {
+    match (local, remote) {
+        (JsonValue::Object(local), JsonValue::Object(remote)) => local
+            .iter()
+            .all(|(k, v)| remote.get(k).is_some_and(|r| same_data(v, r))),
+        (JsonValue::Number(local), JsonValue::Number(remote)) => local.as_f64() == remote.as_f64(),
+        (JsonValue::String(local), JsonValue::String(remote)) => {
+            local.trim_end_matches('.') == remote.trim_end_matches('.')
+        }
+        // Numbers may come back as strings, or the other way around.
+        (JsonValue::Number(n), JsonValue::String(s))
+        | (JsonValue::String(s), JsonValue::Number(n)) => s.parse::<f64>().ok() == n.as_f64(),
+        (local, remote) => local == remote,
+    }
+}
+
+impl std::fmt::Display for DNSType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
             Self::Uninit => "uninit",
             Self::A => "A",
             Self::AAAA => "AAAA",
             Self::CNAME => "CNAME",
-        }
-        .to_string()
+            Self::MX => "MX",
+            Self::TXT => "TXT",
+            Self::SRV => "SRV",
+            Self::CAA => "CAA",
+            Self::NS => "NS",
+            Self::HTTPS => "HTTPS",
+            Self::SVCB => "SVCB",
+            Self::PTR => "PTR",
+            Self::Other => "other",
+        };
+        write!(f, "{}", s)
     }
 }
 
@@ -347,36 +1295,711 @@ enum Auth {
 }
 
 impl Auth {
-    fn http_header(&self) -> (String, String) {
+    fn http_headers(&self) -> Vec<(String, String)> {
         match self {
             Self::ApiToken { api_token } => {
-                let k = "Authorization".to_string();
-                let v = format!("  Bearer {}", api_token);
-                (k, v)
+                vec![("Authorization".to_string(), format!("Bearer {}", api_token))]
             }
             Self::ApiKey {
-                api_key: _,
-                account_email: _,
+                api_key,
+                account_email,
             } => {
-                todo!()
+                vec![
+                    ("X-Auth-Key".to_string(), api_key.clone()),
+                    ("X-Auth-Email".to_string(), account_email.clone()),
+                ]
             }
         }
     }
+
+    // Fails fast on bad credentials, instead of at the first sync.
+    async fn verify(&self, rest: &Restful) -> Result<()> {
+        let url = match self {
+            Self::ApiToken { .. } => "https://api.cloudflare.com/client/v4/user/tokens/verify",
+            Self::ApiKey { .. } => "https://api.cloudflare.com/client/v4/user",
+        };
+
+        let resp = rest.get(url).await.map_err(|e| match e.api_kind() {
+            Some(ApiErrorKind::Auth) => e.context("cloudflare rejected the credentials"),
+            _ => e.context("verify cloudflare credentials failed"),
+        })?;
+
+        if let Self::ApiToken { .. } = self {
+            let status = resp["result"]["status"].as_str().unwrap_or_default();
+            if status != "active" {
+                return Err(AppErr::provider(format!(
+                    "cloudflare api token is not active: {}",
+                    status
+                )));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Cloudflare {
     pub fn from_yaml_value(yaml: YamlValue) -> Result<Self> {
-        let rval: Self = serde_yaml::from_value(yaml)?;
+        let mut rval: Self = serde_yaml::from_value(yaml)?;
+        rval.rest = Restful::new(rval.auth.http_headers(), rval.retry.clone());
+
+        if rval.check_interval == Some(0) {
+            return Err(AppErr::config(
+                "check_interval of a backend must be at least 1".to_string(),
+            ));
+        }
+
+        for zone in rval.zones.iter_mut() {
+            if zone.id.is_empty() && zone.name.is_empty() {
+                return Err(AppErr::config(
+                    "cloudflare zone needs 'id' or 'name'".to_string(),
+                ));
+            }
+
+            if zone.check_interval == Some(0) {
+                let zone_ref = if zone.name.is_empty() {
+                    &zone.id
+                } else {
+                    &zone.name
+                };
+                return Err(AppErr::config(format!(
+                    "zone {}: check_interval must be at least 1",
+                    zone_ref
+                )));
+            }
+
+            for record in zone.records.iter_mut() {
+                record.validate()?;
+                if let Some(prune) = &zone.prune {
+                    prune.ownership.mark(record);
+                }
+            }
+
+            if let Some(Prune {
+                ownership: Ownership::Txt,
+                ..
+            }) = &zone.prune
+            {
+                if zone.owner_id.is_none() {
+                    return Err(AppErr::config(
+                        "prune with txt ownership needs 'owner_id' on the zone".to_string(),
+                    ));
+                }
+            }
+        }
+
         Ok(rval)
     }
 }
 
 #[async_trait]
 impl DNSSync for Cloudflare {
-    async fn sync(&self, cur_v4addr: &str) -> Result<()> {
-        for zone in self.zones.iter() {
-            zone.sync(&self.auth, cur_v4addr).await?;
+    async fn setup(&mut self) -> Result<()> {
+        self.auth.verify(&self.rest).await?;
+        info!("cloudflare credentials verified");
+
+        for zone in self.zones.iter_mut() {
+            zone.resolve(&self.rest).await?;
         }
         Ok(())
     }
+
+    fn check_intervals(&self) -> Vec<Option<u64>> {
+        self.zones
+            .iter()
+            .map(|zone| zone.check_interval.or(self.check_interval))
+            .collect()
+    }
+
+    fn wanted_ips(&self, zones: Zones) -> Vec<(String, IpFamily)> {
+        self.zones
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| zones.contains(*i))
+            .flat_map(|(_, zone)| zone.wanted_ips())
+            .collect()
+    }
+
+    async fn sync(
+        &self,
+        zones: Zones<'_>,
+        addrs: &ResolvedAddrs,
+        state: &StateCache,
+        limit: &Semaphore,
+    ) -> Vec<UnitReport> {
+        let zones = self
+            .zones
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| zones.contains(*i));
+        let syncs = zones.map(|(_, zone)| async move {
+            let _permit = limit
+                .acquire()
+                .await
+                .expect("the semaphore is never closed");
+            UnitReport {
+                backend: "cloudflare".to_string(),
+                zone: zone.name.clone(),
+                result: zone.sync(&self.rest, addrs, state).await,
+            }
+        });
+
+        futures::future::join_all(syncs).await
+    }
+
+    async fn plan(&self, addrs: &ResolvedAddrs) -> Result<Vec<Change>> {
+        let mut changes = Vec::new();
+        for zone in self.zones.iter() {
+            changes.extend(zone.plan(&self.rest, addrs).await?);
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_ip::PubAddrs;
+
+    use serde_json::json;
+
+    // The first zone of a backend configured by `zone`, prepared as at
+    // startup.
+    fn zone(zone: &str) -> Zone {
+        let yaml = format!(
+            "authentication: {{api_token: x}}\nzones:\n- {}",
+            zone.trim().replace('\n', "\n  ")
+        );
+        let mut cloudflare = Cloudflare::from_yaml_value(serde_yaml::from_str(&yaml).unwrap())
+            .expect("valid zone config");
+        cloudflare.zones.remove(0)
+    }
+
+    // Remote records as listed by Cloudflare, `ttl` and `proxied` default to
+    // auto and off.
+    fn remote(records: JsonValue) -> Vec<Record> {
+        let records: Vec<Record> = serde_json::from_value(records).unwrap();
+        records
+            .into_iter()
+            .map(|mut r| {
+                r.ttl = r.ttl.or(Some(1));
+                r.proxied = r.proxied.or(Some(false));
+                r
+            })
+            .collect()
+    }
+
+    fn addrs(v4: &str) -> ResolvedAddrs {
+        let addrs = PubAddrs {
+            v4: Some(v4.parse().unwrap()),
+            v6: None,
+        };
+        [(DEFAULT_SOURCE.to_owned(), addrs)].into_iter().collect()
+    }
+
+    // (kind, remote id, type, content) of each action, in order.
+    fn summary(diff: &Diff) -> Vec<(ChangeKind, &str, String, &str)> {
+        diff.actions
+            .iter()
+            .map(|a| {
+                let content = a.body["content"].as_str().unwrap_or_default();
+                (a.kind, a.id.as_str(), a.dns_type.to_string(), content)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_set_keeps_matches_and_rewrites_the_rest() {
+        let zone = zone(
+            r#"
+name: example.com
+records:
+- {type: A, name: rr.example.com, content: 192.0.2.1}
+- {type: A, name: rr.example.com, content: 192.0.2.2}
+- {type: A, name: rr.example.com, content: 192.0.2.3}
+"#,
+        );
+        let remote = remote(json!([
+            {"id": "r9", "type": "A", "name": "rr.example.com", "content": "198.51.100.9"},
+            {"id": "r2", "type": "A", "name": "RR.example.com.", "content": "192.0.2.2"},
+        ]));
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote)
+            .unwrap();
+        assert_eq!(
+            summary(&diff),
+            vec![
+                (ChangeKind::Update, "r9", "A".into(), "192.0.2.1"),
+                (ChangeKind::Create, "", "A".into(), "192.0.2.3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn surplus_records_are_kept_without_prune() {
+        let zone = zone(
+            r#"
+name: example.com
+records:
+- {type: A, name: www.example.com}
+"#,
+        );
+        let remote = remote(json!([
+            {"id": "r1", "type": "A", "name": "www.example.com", "content": "198.51.100.7"},
+            {"id": "r2", "type": "A", "name": "www.example.com", "content": "203.0.113.1"},
+        ]));
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote)
+            .unwrap();
+        assert!(diff.actions.is_empty());
+    }
+
+    #[test]
+    fn conflicting_type_is_taken_over() {
+        let zone = zone(
+            r#"
+name: example.com
+records:
+- {type: CNAME, name: www.example.com, content: host.example.net}
+"#,
+        );
+        let remote = remote(json!([
+            {"id": "r1", "type": "A", "name": "www.example.com", "content": "192.0.2.1"},
+            {"id": "r2", "type": "MX", "name": "www.example.com", "content": "mx.example.com", "priority": 10},
+        ]));
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote)
+            .unwrap();
+        assert_eq!(
+            summary(&diff),
+            vec![(ChangeKind::Update, "r1", "CNAME".into(), "host.example.net")]
+        );
+        assert_eq!(diff.actions[0].body["type"], "CNAME");
+    }
+
+    #[test]
+    fn conflicting_type_is_kept_once_the_set_is_complete() {
+        let zone = zone(
+            r#"
+name: example.com
+records:
+- {type: A, name: www.example.com}
+"#,
+        );
+        let remote = remote(json!([
+            {"id": "r1", "type": "CNAME", "name": "www.example.com", "content": "host.example.net"},
+            {"id": "r2", "type": "A", "name": "www.example.com", "content": "203.0.113.1"},
+        ]));
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote)
+            .unwrap();
+        assert!(diff.actions.is_empty());
+    }
+
+    #[test]
+    fn set_without_an_address_is_skipped() {
+        let zone = zone(
+            r#"
+name: example.com
+records:
+- {type: AAAA, name: www.example.com}
+- {type: A, name: www.example.com}
+"#,
+        );
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), Vec::new())
+            .unwrap();
+        assert_eq!(
+            summary(&diff),
+            vec![(ChangeKind::Create, "", "A".into(), "203.0.113.1")]
+        );
+        assert_eq!(diff.skipped.len(), 1);
+        assert!(matches!(diff.skipped[0], AppErr::IpDiscovery { .. }));
+    }
+
+    #[test]
+    fn plan_without_an_address_fails() {
+        let zone = zone(
+            r#"
+name: example.com
+records:
+- {type: A, name: www.example.com}
+- {type: AAAA, name: www.example.com}
+"#,
+        );
+
+        let err = zone
+            .planned_changes(&addrs("203.0.113.1"), Vec::new())
+            .expect_err("the AAAA set has no address");
+        assert!(matches!(err, AppErr::IpDiscovery { .. }));
+        assert_eq!(err.exit_code(), EXIT_IP_DISCOVERY);
+
+        let both = PubAddrs {
+            v4: Some("203.0.113.1".parse().unwrap()),
+            v6: Some("2001:db8::1".parse().unwrap()),
+        };
+        let both: ResolvedAddrs = [(DEFAULT_SOURCE.to_owned(), both)].into_iter().collect();
+        assert_eq!(zone.planned_changes(&both, Vec::new()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn content_is_compared_normalized() {
+        let zone = zone(
+            r#"
+name: example.com
+records:
+- {type: AAAA, name: v6.example.com, content: "2001:db8::1"}
+- {type: CNAME, name: alias.example.com, content: Host.Example.net.}
+- {type: TXT, name: txt.example.com, content: "v=spf1 -all"}
+"#,
+        );
+        let remote = remote(json!([
+            {"id": "r1", "type": "AAAA", "name": "v6.example.com", "content": "2001:0db8:0::1"},
+            {"id": "r2", "type": "CNAME", "name": "alias.example.com", "content": "host.example.net"},
+            {"id": "r3", "type": "TXT", "name": "txt.example.com", "content": "\"v=spf1 \" \"-all\""},
+        ]));
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote)
+            .unwrap();
+        assert!(diff.actions.is_empty());
+    }
+
+    fn marked_remote() -> Vec<Record> {
+        remote(json!([
+            {"id": "r1", "type": "A", "name": "www.example.com", "content": "203.0.113.1", "comment": "[dns-syncer]"},
+            {"id": "r2", "type": "A", "name": "old.example.com", "content": "192.0.2.9", "comment": "[dns-syncer]"},
+            {"id": "r3", "type": "A", "name": "hand.example.com", "content": "192.0.2.8"},
+            {"id": "r4", "type": "A", "name": "www.example.com", "content": "192.0.2.7", "comment": "was [dns-syncer]"},
+            {"id": "r5", "type": "A", "name": "www.example.com", "content": "192.0.2.6"},
+        ]))
+    }
+
+    #[test]
+    fn prune_deletes_only_owned_records() {
+        let zone = zone(
+            r#"
+name: example.com
+prune: {owner: comment, marker: "[dns-syncer]"}
+records:
+- {type: A, name: www.example.com}
+"#,
+        );
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), marked_remote())
+            .unwrap();
+        assert_eq!(
+            summary(&diff),
+            vec![
+                (ChangeKind::Delete, "r2", "A".into(), ""),
+                (ChangeKind::Delete, "r4", "A".into(), ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn prune_over_the_cap_fails() {
+        let zone = zone(
+            r#"
+name: example.com
+prune: {owner: comment, marker: "[dns-syncer]", max_deletions: 1}
+records:
+- {type: A, name: www.example.com}
+"#,
+        );
+
+        let err = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), marked_remote())
+            .err()
+            .expect("2 deletions exceed the cap of 1");
+        assert!(matches!(err, AppErr::Provider { .. }));
+        assert!(err.to_string().contains("max_deletions 1"));
+    }
+
+    #[test]
+    fn nothing_is_deleted_without_prune() {
+        let zone = zone(
+            r#"
+name: example.com
+records:
+- {type: A, name: www.example.com, comment: "[dns-syncer]"}
+"#,
+        );
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), marked_remote())
+            .unwrap();
+        assert!(diff.actions.is_empty());
+    }
+
+    #[test]
+    fn prune_by_tag_marks_and_deletes() {
+        let zone = zone(
+            r#"
+name: example.com
+prune: {owner: tag, tag: "managed-by:dns-syncer"}
+records:
+- {type: A, name: new.example.com, content: 192.0.2.1}
+"#,
+        );
+        let remote = remote(json!([
+            {"id": "r1", "type": "A", "name": "old.example.com", "content": "192.0.2.9", "tags": ["managed-by:dns-syncer"]},
+            {"id": "r2", "type": "A", "name": "hand.example.com", "content": "192.0.2.8", "tags": ["team:web"]},
+        ]));
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote)
+            .unwrap();
+        assert_eq!(
+            summary(&diff),
+            vec![
+                (ChangeKind::Delete, "r1", "A".into(), ""),
+                (ChangeKind::Create, "", "A".into(), "192.0.2.1"),
+            ]
+        );
+        assert_eq!(
+            diff.actions[1].body["tags"],
+            json!(["managed-by:dns-syncer"])
+        );
+    }
+
+    #[test]
+    fn registry_parses_registrations() {
+        let remote = remote(json!([
+            {"id": "t1", "type": "TXT", "name": "_dns-syncer.www.example.com", "content": "\"heritage=dns-syncer,owner=home,types=A/AAAA\""},
+            {"id": "t2", "type": "TXT", "name": "_dns-syncer.api.example.com", "content": "heritage=dns-syncer,owner=other,types=CNAME"},
+            {"id": "t3", "type": "TXT", "name": "_dns-syncer.junk.example.com", "content": "owner=home"},
+            {"id": "r1", "type": "A", "name": "www.example.com", "content": "192.0.2.1"},
+            {"id": "r2", "type": "MX", "name": "www.example.com", "content": "mx.example.com", "priority": 10},
+        ]));
+        let registry = Registry::from_remote("home", &remote);
+
+        assert_eq!(registry.owner_of("WWW.example.com."), Some("home"));
+        assert!(registry.is_mine("www.example.com"));
+        assert_eq!(registry.owner_of("api.example.com"), Some("other"));
+        assert!(!registry.is_mine("api.example.com"));
+        assert_eq!(registry.owner_of("junk.example.com"), None);
+        assert_eq!(registry.get("www.example.com").unwrap().index, 0);
+
+        assert!(registry.owns(&remote[3]));
+        assert!(!registry.owns(&remote[4]));
+
+        let record = registry.record("new.example.com.", &["A".into(), "AAAA".into()]);
+        assert_eq!(record.name, "_dns-syncer.new.example.com");
+        assert_eq!(
+            record.content,
+            "heritage=dns-syncer,owner=home,types=A/AAAA"
+        );
+    }
+
+    #[test]
+    fn names_of_another_owner_are_skipped() {
+        let zone = zone(
+            r#"
+name: example.com
+owner_id: home
+records:
+- {type: A, name: api.example.com, content: 192.0.2.1}
+"#,
+        );
+        let remote = remote(json!([
+            {"id": "t1", "type": "TXT", "name": "_dns-syncer.api.example.com", "content": "heritage=dns-syncer,owner=other,types=A"},
+            {"id": "r1", "type": "A", "name": "api.example.com", "content": "198.51.100.1"},
+        ]));
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote)
+            .unwrap();
+        assert!(diff.actions.is_empty());
+    }
+
+    #[test]
+    fn registry_is_created_and_updated() {
+        let zone = zone(
+            r#"
+name: example.com
+owner_id: home
+records:
+- {type: A, name: www.example.com}
+- {type: AAAA, name: www.example.com, content: "2001:db8::1"}
+- {type: A, name: new.example.com, content: 192.0.2.1}
+"#,
+        );
+        let remote = remote(json!([
+            {"id": "t1", "type": "TXT", "name": "_dns-syncer.www.example.com", "content": "\"heritage=dns-syncer,owner=home,types=A\""},
+            {"id": "r1", "type": "A", "name": "www.example.com", "content": "203.0.113.1"},
+        ]));
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote)
+            .unwrap();
+        assert_eq!(
+            summary(&diff),
+            vec![
+                (
+                    ChangeKind::Update,
+                    "t1",
+                    "TXT".into(),
+                    "heritage=dns-syncer,owner=home,types=A/AAAA"
+                ),
+                (ChangeKind::Create, "", "AAAA".into(), "2001:db8::1"),
+                (ChangeKind::Create, "", "A".into(), "192.0.2.1"),
+                (
+                    ChangeKind::Create,
+                    "",
+                    "TXT".into(),
+                    "heritage=dns-syncer,owner=home,types=A"
+                ),
+            ]
+        );
+        assert_eq!(diff.actions[3].name, "_dns-syncer.new.example.com");
+    }
+
+    // Applies the actions to the remote records, as Cloudflare would.
+    fn apply(remote_records: &mut Vec<Record>, diff: Diff) {
+        for action in diff.actions.into_iter() {
+            match action.kind {
+                ChangeKind::Create => {
+                    let mut record: Record = serde_json::from_value(action.body).unwrap();
+                    record.id = format!("n{}", remote_records.len());
+                    remote_records.push(record);
+                }
+                ChangeKind::Update => {
+                    let remote = remote_records
+                        .iter_mut()
+                        .find(|r| r.id == action.id)
+                        .unwrap();
+                    let id = remote.id.clone();
+                    let mut old = serde_json::to_value(&*remote).unwrap();
+                    old.as_object_mut()
+                        .unwrap()
+                        .extend(action.body.as_object().unwrap().clone());
+                    *remote = serde_json::from_value(old).unwrap();
+                    remote.id = id;
+                }
+                ChangeKind::Delete => remote_records.retain(|r| r.id != action.id),
+            }
+        }
+    }
+
+    #[test]
+    fn records_of_others_are_not_registered_nor_pruned_later() {
+        let zone = zone(
+            r#"
+name: example.com
+owner_id: home
+prune: {owner: txt}
+records:
+- {type: A, name: www.example.com}
+- {type: A, name: api.example.com, content: 192.0.2.1}
+"#,
+        );
+        let mut remote_records = remote(json!([
+            {"id": "r1", "type": "A", "name": "www.example.com", "content": "203.0.113.1"},
+            {"id": "r2", "type": "A", "name": "www.example.com", "content": "192.0.2.50"},
+        ]));
+
+        for _ in 0..2 {
+            let diff = zone
+                .get_actions_by_diff(&addrs("203.0.113.1"), remote_records.clone())
+                .unwrap();
+            assert!(diff.actions.iter().all(|a| a.kind != ChangeKind::Delete));
+            apply(&mut remote_records, diff);
+        }
+
+        let names: Vec<&str> = remote_records.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "www.example.com",
+                "www.example.com",
+                "api.example.com",
+                "_dns-syncer.api.example.com"
+            ]
+        );
+
+        // Once the record of another tool is gone, the name is registered.
+        remote_records.retain(|r| r.id != "r2");
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote_records.clone())
+            .unwrap();
+        assert_eq!(
+            summary(&diff),
+            vec![(
+                ChangeKind::Create,
+                "",
+                "TXT".into(),
+                "heritage=dns-syncer,owner=home,types=A"
+            )]
+        );
+        apply(&mut remote_records, diff);
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote_records)
+            .unwrap();
+        assert!(diff.actions.is_empty());
+    }
+
+    #[test]
+    fn prune_by_registry_deletes_registered_types_and_the_registration() {
+        let zone = zone(
+            r#"
+name: example.com
+owner_id: home
+prune: {owner: txt}
+records:
+- {type: A, name: www.example.com}
+"#,
+        );
+        let remote = remote(json!([
+            {"id": "t1", "type": "TXT", "name": "_dns-syncer.www.example.com", "content": "heritage=dns-syncer,owner=home,types=A"},
+            {"id": "r1", "type": "A", "name": "www.example.com", "content": "203.0.113.1"},
+            {"id": "t2", "type": "TXT", "name": "_dns-syncer.old.example.com", "content": "heritage=dns-syncer,owner=home,types=A"},
+            {"id": "r2", "type": "A", "name": "old.example.com", "content": "192.0.2.9"},
+            {"id": "r3", "type": "MX", "name": "old.example.com", "content": "mx.example.com", "priority": 10},
+            {"id": "t3", "type": "TXT", "name": "_dns-syncer.api.example.com", "content": "heritage=dns-syncer,owner=other,types=A"},
+            {"id": "r4", "type": "A", "name": "api.example.com", "content": "192.0.2.4"},
+        ]));
+
+        let diff = zone
+            .get_actions_by_diff(&addrs("203.0.113.1"), remote)
+            .unwrap();
+        assert_eq!(
+            summary(&diff),
+            vec![
+                (ChangeKind::Delete, "r2", "A".into(), ""),
+                (ChangeKind::Delete, "t2", "TXT".into(), ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn unquote_txt_joins_quoted_strings() {
+        assert_eq!(unquote_txt(r#""v=spf1 " "-all""#), "v=spf1 -all");
+        assert_eq!(unquote_txt(r#""say \"hi\"""#), r#"say "hi""#);
+        assert_eq!(unquote_txt("plain text"), "plain text");
+        assert_eq!(unquote_txt(r#""a" b "c""#), r#""a" b "c""#);
+        assert_eq!(unquote_txt(r#"""#), r#"""#);
+    }
+
+    #[test]
+    fn same_data_ignores_extra_remote_fields() {
+        let local = json!({"priority": 10, "weight": 5, "port": 5060, "target": "sip.example.com"});
+        let remote = json!({
+            "priority": 10, "weight": "5", "port": 5060.0,
+            "target": "sip.example.com.", "name": "_sip._udp"
+        });
+        assert!(same_data(&local, &remote));
+        assert!(!same_data(&remote, &local));
+        assert!(!same_data(
+            &local,
+            &json!({"priority": 10, "weight": 5, "port": 5061})
+        ));
+        assert!(!same_data(&json!({"flags": 0}), &json!({"flags": "x"})));
+    }
 }
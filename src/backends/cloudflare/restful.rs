@@ -1,86 +1,518 @@
 use crate::err::*;
-use reqwest::Response;
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonVal;
+use tracing::warn;
 
-pub struct Restful;
+use std::time::Duration;
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    // Including the first one, 1 disables retrying.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    // The delay before the first retry, doubled for each further one.
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    // Also caps the delay asked for by `Retry-After`.
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    4
+}
+
+fn default_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_max_delay_ms() -> u64 {
+    30_000
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            base_delay_ms: default_base_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Exponential backoff with jitter, so that clients failing together do
+    // not retry together.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_delay_ms);
+        Duration::from_millis(fastrand::u64(delay / 2..=delay))
+    }
+}
+
+#[derive(Default)]
+pub struct Restful {
+    client: reqwest::Client,
+    headers: Vec<(String, String)>,
+    retry: RetryPolicy,
+}
+
+// The headers carry credentials.
+impl std::fmt::Debug for Restful {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Restful")
+            .field("retry", &self.retry)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ReqMethod {
     Get,
     Post,
     Patch,
+    Delete,
+}
+
+impl ReqMethod {
+    // Whether sending the request twice has the same effect as sending it
+    // once. A POST creates a record each time.
+    fn is_idempotent(&self) -> bool {
+        !matches!(self, Self::Post)
+    }
 }
 
 impl Restful {
-    fn request_builder(
-        url: &str,
-        method: ReqMethod,
-        headers: Option<Vec<(&str, &str)>>,
-    ) -> Result<reqwest::RequestBuilder> {
-        let client = reqwest::Client::new();
+    pub fn new(headers: Vec<(String, String)>, retry: RetryPolicy) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            headers,
+            retry,
+        }
+    }
 
+    fn request_builder(&self, url: &str, method: ReqMethod) -> Result<reqwest::RequestBuilder> {
         let mut builder = match method {
-            ReqMethod::Get => client.get(url),
-            ReqMethod::Post => client.post(url),
-            ReqMethod::Patch => client.patch(url),
+            ReqMethod::Get => self.client.get(url),
+            ReqMethod::Post => self.client.post(url),
+            ReqMethod::Patch => self.client.patch(url),
+            ReqMethod::Delete => self.client.delete(url),
         };
 
         builder = builder.header("Content-Type", "application/json");
 
-        if let Some(headers) = headers {
-            for header in headers.into_iter() {
-                builder = builder.header(header.0, header.1);
-            }
+        for (k, v) in self.headers.iter() {
+            builder = builder.header(k, v);
         }
 
         Ok(builder)
     }
 
-    async fn non_200_to_error(resp: Response) -> Result<String> {
-        let status = resp.status();
-        if status != 200 {
-            return Err(AppErr {
-                msg: format!("Not a 200 response: {}", resp.text().await?),
-            });
+    // Cloudflare wraps every response in an envelope with `success`, `errors`
+    // and `messages`. A request failed if the status is not 200 or if
+    // `success` is false, which Cloudflare also answers with 200 at times.
+    fn parse_envelope(status: u16, headers: &HeaderMap, body: &str) -> Result<JsonVal> {
+        let ray_id = headers
+            .get("cf-ray")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+
+        let json: JsonVal = match serde_json::from_str(body) {
+            Ok(json) => json,
+            // E.g. an HTML error page of a proxy in between.
+            Err(_) if status != 200 => {
+                return Err(AppErr::api(ApiError {
+                    status,
+                    code: None,
+                    message: body.trim().chars().take(200).collect(),
+                    ray_id,
+                }))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        for msg in envelope_entries(&json["messages"]) {
+            warn!("cloudflare: {}", msg.1);
         }
-        let body = resp.text().await?;
-        Ok(body)
+
+        if status == 200 && json["success"].as_bool() != Some(false) {
+            return Ok(json);
+        }
+
+        let errors = envelope_entries(&json["errors"]);
+        let message = if errors.is_empty() {
+            format!("request failed: {}", body.trim())
+        } else if errors.len() == 1 {
+            errors[0].1.clone()
+        } else {
+            errors
+                .iter()
+                .map(|(code, msg)| match code {
+                    Some(code) => format!("[{}] {}", code, msg),
+                    None => msg.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join("; ")
+        };
+
+        Err(AppErr::api(ApiError {
+            status,
+            code: errors.iter().find_map(|e| e.0),
+            message,
+            ray_id,
+        }))
     }
 
-    pub async fn get(url: &str, headers: Option<Vec<(&str, &str)>>) -> Result<serde_json::Value> {
-        let builder = Self::request_builder(url, ReqMethod::Get, headers)?;
+    // Sends the request, retrying on rate limiting, server errors and broken
+    // connections. Requests which are not idempotent are only retried if they
+    // cannot have been processed: the connection failed or the rate limit
+    // rejected them.
+    async fn send(&self, url: &str, method: ReqMethod, json: Option<&JsonVal>) -> Result<Response> {
+        let mut attempt = 1;
+        loop {
+            let mut builder = self.request_builder(url, method)?;
+            if let Some(json) = json {
+                builder = builder.json(json);
+            }
+
+            let result = builder.send().await;
+            let can_retry = attempt < self.retry.max_attempts;
+
+            let delay = match &result {
+                Ok(resp) if resp.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    Some(retry_after(resp.headers()).unwrap_or(self.retry.backoff(attempt - 1)))
+                }
+                Ok(resp) if resp.status().is_server_error() && method.is_idempotent() => {
+                    Some(retry_after(resp.headers()).unwrap_or(self.retry.backoff(attempt - 1)))
+                }
+                Err(e) if e.is_connect() || (method.is_idempotent() && e.is_request()) => {
+                    Some(self.retry.backoff(attempt - 1))
+                }
+                _ => None,
+            };
 
-        let resp = builder.send().await?;
-        let body = Self::non_200_to_error(resp).await?;
-        let json: serde_json::Value = serde_json::from_str(&body)?;
+            let delay = match delay {
+                Some(delay) if can_retry => {
+                    delay.min(Duration::from_millis(self.retry.max_delay_ms))
+                }
+                _ => return Ok(result?),
+            };
 
-        Ok(json)
+            let reason = match &result {
+                Ok(resp) => resp.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            warn!(
+                "{:?} {} failed ({}), retry {}/{} in {:?}",
+                method,
+                url,
+                reason,
+                attempt,
+                self.retry.max_attempts - 1,
+                delay
+            );
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
     }
 
-    pub async fn post(
+    async fn request(
+        &self,
         url: &str,
-        headers: Option<Vec<(&str, &str)>>,
-        json: &JsonVal,
-    ) -> Result<serde_json::Value> {
-        let builder = Self::request_builder(url, ReqMethod::Post, headers)?;
+        method: ReqMethod,
+        json: Option<&JsonVal>,
+    ) -> Result<JsonVal> {
+        let resp = self.send(url, method, json).await?;
+        let status = resp.status().as_u16();
+        let headers = resp.headers().clone();
+        let body = resp.text().await?;
+        Self::parse_envelope(status, &headers, &body)
+    }
 
-        let resp = builder.json(json).send().await?;
-        let body = Self::non_200_to_error(resp).await?;
-        let json: serde_json::Value = serde_json::from_str(&body)?;
+    pub async fn get(&self, url: &str) -> Result<serde_json::Value> {
+        self.request(url, ReqMethod::Get, None).await
+    }
 
-        Ok(json)
+    pub async fn post(&self, url: &str, json: &JsonVal) -> Result<serde_json::Value> {
+        self.request(url, ReqMethod::Post, Some(json)).await
     }
 
-    pub async fn patch(
-        url: &str,
-        headers: Option<Vec<(&str, &str)>>,
-        json: &JsonVal,
-    ) -> Result<serde_json::Value> {
-        let builder = Self::request_builder(url, ReqMethod::Patch, headers)?;
+    pub async fn patch(&self, url: &str, json: &JsonVal) -> Result<serde_json::Value> {
+        self.request(url, ReqMethod::Patch, Some(json)).await
+    }
 
-        let resp = builder.json(json).send().await?;
-        let body = Self::non_200_to_error(resp).await?;
-        let json: serde_json::Value = serde_json::from_str(&body)?;
+    pub async fn delete(&self, url: &str) -> Result<serde_json::Value> {
+        self.request(url, ReqMethod::Delete, None).await
+    }
+}
+
+// Only the delay in seconds form of `Retry-After` is supported, which is the
+// one Cloudflare sends.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?;
+    let secs: u64 = value.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+// The `errors` and `messages` of the envelope are lists of `code` and
+// `message` objects, messages are sometimes plain strings.
+fn envelope_entries(list: &JsonVal) -> Vec<(Option<i64>, String)> {
+    let Some(list) = list.as_array() else {
+        return vec![];
+    };
+    list.iter()
+        .map(|entry| match entry {
+            JsonVal::String(msg) => (None, msg.clone()),
+            _ => (
+                entry["code"].as_i64(),
+                entry["message"]
+                    .as_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| entry.to_string()),
+            ),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use reqwest::header::HeaderValue;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn headers(ray_id: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(ray_id) = ray_id {
+            headers.insert("cf-ray", HeaderValue::from_str(ray_id).unwrap());
+        }
+        headers
+    }
+
+    fn api_error(err: AppErr) -> ApiError {
+        match err {
+            AppErr::Provider { api: Some(api), .. } => api,
+            err => panic!("not an api error: {:?}", err),
+        }
+    }
+
+    // A server answering the requests it accepts with `statuses` in turn,
+    // each with `Retry-After: 0`. Returns its url and how many requests it
+    // got.
+    async fn stub(statuses: &'static [u16]) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Reads the headers and as much of the body as they announce.
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&req);
+                    let Some(end) = text.find("\r\n\r\n") else {
+                        continue;
+                    };
+                    let len = text
+                        .lines()
+                        .find_map(|l| {
+                            l.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().parse().unwrap())
+                        })
+                        .unwrap_or(0usize);
+                    if req.len() >= end + 4 + len {
+                        break;
+                    }
+                }
+
+                let i = counter.fetch_add(1, Ordering::SeqCst);
+                let status = statuses[i.min(statuses.len() - 1)];
+                let body = format!(
+                    r#"{{"success": {}, "errors": [], "result": {}}}"#,
+                    status == 200,
+                    i
+                );
+                let resp = format!(
+                    "HTTP/1.1 {} Stub\r\nRetry-After: 0\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        (url, count)
+    }
+
+    fn restful() -> Restful {
+        Restful::new(
+            vec![],
+            RetryPolicy {
+                max_attempts: 3,
+                base_delay_ms: 1,
+                max_delay_ms: 10,
+            },
+        )
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+        };
+        for _ in 0..100 {
+            for (retry, max) in [
+                (0, 100),
+                (1, 200),
+                (2, 400),
+                (3, 800),
+                (4, 1000),
+                (40, 1000),
+            ] {
+                let delay = policy.backoff(retry).as_millis() as u64;
+                assert!(
+                    (max / 2..=max).contains(&delay),
+                    "retry {}: {}ms",
+                    retry,
+                    delay
+                );
+            }
+        }
+
+        // Large delays saturate instead of overflowing.
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay_ms: u64::MAX / 2,
+            max_delay_ms: u64::MAX,
+        };
+        assert!(policy.backoff(16) >= Duration::from_millis(u64::MAX / 2));
+    }
+
+    #[test]
+    fn retry_after_takes_seconds() {
+        let header = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                reqwest::header::RETRY_AFTER,
+                HeaderValue::from_str(value).unwrap(),
+            );
+            headers
+        };
+
+        assert_eq!(retry_after(&header("120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&header(" 0 ")), Some(Duration::ZERO));
+        assert_eq!(retry_after(&header("Wed, 21 Oct 2015 07:28:00 GMT")), None);
+        assert_eq!(retry_after(&header("-1")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_for_idempotent_requests_only() {
+        let (url, count) = stub(&[503, 200]).await;
+        let json = restful().get(&url).await.unwrap();
+        assert_eq!(json["result"], 1);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        let (url, count) = stub(&[503, 200]).await;
+        let err = restful()
+            .post(&url, &serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert_eq!(err.api_kind(), Some(ApiErrorKind::Server));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_retried_up_to_max_attempts() {
+        let (url, count) = stub(&[429, 200]).await;
+        restful().post(&url, &serde_json::json!({})).await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        let (url, count) = stub(&[429]).await;
+        let err = restful().delete(&url).await.unwrap_err();
+        assert_eq!(err.api_kind(), Some(ApiErrorKind::RateLimited));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn successful_envelope_is_returned() {
+        let body =
+            r#"{"success": true, "errors": [], "messages": ["note"], "result": {"id": "1"}}"#;
+        let json = Restful::parse_envelope(200, &headers(None), body).unwrap();
+        assert_eq!(json["result"]["id"], "1");
+    }
+
+    #[test]
+    fn unsuccessful_envelope_with_200_fails() {
+        let body =
+            r#"{"success": false, "errors": [{"code": 9109, "message": "Invalid access token"}]}"#;
+        let err = Restful::parse_envelope(200, &headers(Some("8a1b2c3d4e5f-AMS")), body);
+        let api = api_error(err.unwrap_err());
+
+        assert_eq!(api.status, 200);
+        assert_eq!(api.code, Some(9109));
+        assert_eq!(api.message, "Invalid access token");
+        assert_eq!(api.ray_id.as_deref(), Some("8a1b2c3d4e5f-AMS"));
+        assert_eq!(api.kind(), ApiErrorKind::Auth);
+    }
+
+    #[test]
+    fn errors_are_joined_and_the_first_code_is_kept() {
+        let body = r#"{"success": false, "errors": [
+            {"message": "no code"},
+            {"code": 81057, "message": "Record already exists."},
+            {"code": 1004, "message": "DNS Validation Error"}
+        ]}"#;
+        let api = api_error(Restful::parse_envelope(400, &headers(None), body).unwrap_err());
+
+        assert_eq!(api.code, Some(81057));
+        assert_eq!(
+            api.message,
+            "no code; [81057] Record already exists.; [1004] DNS Validation Error"
+        );
+        assert_eq!(api.ray_id, None);
+        assert_eq!(api.kind(), ApiErrorKind::Validation);
+    }
+
+    #[test]
+    fn non_json_error_page_fails_with_its_text() {
+        let body = "<html><body>502 Bad Gateway</body></html>\n";
+        let api = api_error(Restful::parse_envelope(502, &headers(Some("ray")), body).unwrap_err());
+
+        assert_eq!(api.status, 502);
+        assert_eq!(api.code, None);
+        assert_eq!(api.message, body.trim());
+        assert_eq!(api.ray_id.as_deref(), Some("ray"));
+        assert_eq!(api.kind(), ApiErrorKind::Server);
+
+        // Only the start of a long page is kept.
+        let body = "x".repeat(1000);
+        let api = api_error(Restful::parse_envelope(503, &headers(None), &body).unwrap_err());
+        assert_eq!(api.message.len(), 200);
+    }
 
-        Ok(json)
+    #[test]
+    fn non_json_success_fails() {
+        let err = Restful::parse_envelope(200, &headers(None), "not json").unwrap_err();
+        assert!(err.api_kind().is_none(), "{:?}", err);
     }
 }
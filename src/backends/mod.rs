@@ -2,12 +2,143 @@ mod cloudflare;
 pub use cloudflare::*;
 
 use crate::err::*;
+use crate::get_ip::{IpFamily, ResolvedAddrs};
+use crate::state::StateCache;
 
 use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use tokio::sync::Semaphore;
+use tracing::{debug, error, info};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Create,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordKey {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub dns_type: String,
+}
+
+// A planned change of one record, in a form independent of the provider.
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    pub backend: String,
+    pub zone: String,
+    pub action: ChangeKind,
+    pub record: RecordKey,
+    // The record before and after the change, as the provider represents it.
+    pub old: Option<JsonValue>,
+    pub new: Option<JsonValue>,
+}
+
+// Selects zones of a backend, by their position in its list of zones.
+#[derive(Debug, Clone, Copy)]
+pub enum Zones<'a> {
+    All,
+    Only(&'a [usize]),
+}
+
+impl Zones<'_> {
+    pub fn contains(&self, zone: usize) -> bool {
+        match self {
+            Zones::All => true,
+            Zones::Only(zones) => zones.contains(&zone),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SyncStatus {
+    // Nothing changed locally since the last successful sync.
+    Unchanged,
+    Synced { changes: usize },
+}
+
+// The outcome of syncing one zone of a backend.
+#[derive(Debug)]
+pub struct UnitReport {
+    pub backend: String,
+    pub zone: String,
+    pub result: Result<SyncStatus>,
+}
+
+// The outcome of one sync cycle. A failed zone does not stop the others.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub units: Vec<UnitReport>,
+}
+
+impl SyncReport {
+    pub fn log(&self) {
+        let (mut synced, mut unchanged, mut failed) = (0, 0, 0);
+        for unit in self.units.iter() {
+            match &unit.result {
+                Ok(SyncStatus::Unchanged) => unchanged += 1,
+                Ok(SyncStatus::Synced { changes }) => {
+                    synced += 1;
+                    debug!(
+                        "{}/{}: {} changes applied",
+                        unit.backend, unit.zone, changes
+                    );
+                }
+                Err(e) => {
+                    failed += 1;
+                    error!("{}/{}: sync failed: {}", unit.backend, unit.zone, e);
+                }
+            }
+        }
+
+        info!(
+            "sync cycle: {} zones, {} synced, {} unchanged, {} failed",
+            self.units.len(),
+            synced,
+            unchanged,
+            failed
+        );
+    }
+
+    // The error of the first failed zone, if any.
+    pub fn into_result(self) -> Result<()> {
+        for unit in self.units.into_iter() {
+            if let Err(e) = unit.result {
+                return Err(e.context(&format!("{}/{}", unit.backend, unit.zone)));
+            }
+        }
+        Ok(())
+    }
+}
 
 #[async_trait]
 pub trait DNSSync {
-    async fn sync(&self, v4addr: &str) -> Result<()>;
+    // Prepares the backend before the first sync, e.g. verifies credentials.
+    async fn setup(&mut self) -> Result<()>;
+
+    // The check interval of each zone, if set on the zone or the backend.
+    fn check_intervals(&self) -> Vec<Option<u64>>;
+
+    // The ip sources, and the address families of them, that records of the
+    // zones take their content from.
+    fn wanted_ips(&self, zones: Zones) -> Vec<(String, IpFamily)>;
+
+    // Syncs the zones, holding a permit of `limit` while a zone is synced.
+    async fn sync(
+        &self,
+        zones: Zones<'_>,
+        addrs: &ResolvedAddrs,
+        state: &StateCache,
+        limit: &Semaphore,
+    ) -> Vec<UnitReport>;
+
+    // Computes and prints the changes a sync would make, without applying
+    // them or consulting the state cache.
+    async fn plan(&self, addrs: &ResolvedAddrs) -> Result<Vec<Change>>;
 }
 
 #[derive(Debug)]
@@ -17,9 +148,39 @@ pub enum Backend {
 
 #[async_trait]
 impl DNSSync for Backend {
-    async fn sync(&self, v4addr: &str) -> Result<()> {
+    async fn setup(&mut self) -> Result<()> {
+        match self {
+            Backend::Cloudflare(cloudflare) => cloudflare.setup().await,
+        }
+    }
+
+    fn check_intervals(&self) -> Vec<Option<u64>> {
+        match self {
+            Backend::Cloudflare(cloudflare) => cloudflare.check_intervals(),
+        }
+    }
+
+    fn wanted_ips(&self, zones: Zones) -> Vec<(String, IpFamily)> {
+        match self {
+            Backend::Cloudflare(cloudflare) => cloudflare.wanted_ips(zones),
+        }
+    }
+
+    async fn sync(
+        &self,
+        zones: Zones<'_>,
+        addrs: &ResolvedAddrs,
+        state: &StateCache,
+        limit: &Semaphore,
+    ) -> Vec<UnitReport> {
+        match self {
+            Backend::Cloudflare(cloudflare) => cloudflare.sync(zones, addrs, state, limit).await,
+        }
+    }
+
+    async fn plan(&self, addrs: &ResolvedAddrs) -> Result<Vec<Change>> {
         match self {
-            Backend::Cloudflare(cloudflare) => cloudflare.sync(v4addr).await,
+            Backend::Cloudflare(cloudflare) => cloudflare.plan(addrs).await,
         }
     }
 }
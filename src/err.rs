@@ -1,46 +1,340 @@
+use std::error::Error as StdError;
+
 pub type Result<T> = std::result::Result<T, AppErr>;
 
+type Source = Box<dyn StdError + Send + Sync>;
+
+// Exit codes of the process, taken from sysexits.h. A shutdown by a signal
+// exits with 0, clap exits with 2 on usage errors and a dry run with 3 if
+// changes are pending.
+pub const EXIT_CONFIG: i32 = 78;
+pub const EXIT_IO: i32 = 74;
+pub const EXIT_NETWORK: i32 = 69;
+pub const EXIT_PROVIDER: i32 = 76;
+pub const EXIT_AUTH: i32 = 77;
+pub const EXIT_IP_DISCOVERY: i32 = 68;
+
 #[derive(Debug)]
-pub struct AppErr {
-    pub msg: String,
+pub enum AppErr {
+    // The configuration is invalid, or does not match the provider account.
+    Config {
+        msg: String,
+        source: Option<Source>,
+    },
+    // Reading or writing local files.
+    Io {
+        msg: String,
+        source: Option<Source>,
+    },
+    // A request could not be sent or got no answer.
+    Http {
+        msg: String,
+        source: Option<Source>,
+    },
+    // The provider refused a request or answered with something unexpected.
+    Provider {
+        msg: String,
+        api: Option<ApiError>,
+        source: Option<Source>,
+    },
+    // No public address could be found, or the sources disagree.
+    IpDiscovery {
+        msg: String,
+        source: Option<Source>,
+    },
+    // A signal asked the process to quit.
+    Shutdown {
+        signal: &'static str,
+    },
+}
+
+impl AppErr {
+    pub fn config(msg: String) -> Self {
+        Self::Config { msg, source: None }
+    }
+
+    pub fn provider(msg: String) -> Self {
+        Self::Provider {
+            msg,
+            api: None,
+            source: None,
+        }
+    }
+
+    pub fn api(err: ApiError) -> Self {
+        Self::Provider {
+            msg: err.to_string(),
+            api: Some(err),
+            source: None,
+        }
+    }
+
+    pub fn ip_discovery(msg: String) -> Self {
+        Self::IpDiscovery { msg, source: None }
+    }
+
+    pub fn shutdown(signal: &'static str) -> Self {
+        Self::Shutdown { signal }
+    }
+
+    // Wraps the error into one of the same kind, which describes what was
+    // being done when it happened.
+    pub fn context(self, ctx: &str) -> Self {
+        let msg = ctx.to_string();
+        match self {
+            Self::Config { .. } => Self::Config {
+                msg,
+                source: Some(Box::new(self)),
+            },
+            Self::Io { .. } => Self::Io {
+                msg,
+                source: Some(Box::new(self)),
+            },
+            Self::Http { .. } => Self::Http {
+                msg,
+                source: Some(Box::new(self)),
+            },
+            Self::Provider { .. } => Self::Provider {
+                msg,
+                api: None,
+                source: Some(Box::new(self)),
+            },
+            Self::IpDiscovery { .. } => Self::IpDiscovery {
+                msg,
+                source: Some(Box::new(self)),
+            },
+            Self::Shutdown { .. } => self,
+        }
+    }
+
+    // The kind of the API error this error was caused by, if any.
+    pub fn api_kind(&self) -> Option<ApiErrorKind> {
+        if let Self::Provider { api: Some(api), .. } = self {
+            return Some(api.kind());
+        }
+
+        let mut source = self.source();
+        while let Some(err) = source {
+            if let Some(AppErr::Provider { api: Some(api), .. }) = err.downcast_ref::<AppErr>() {
+                return Some(api.kind());
+            }
+            source = err.source();
+        }
+        None
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config { .. } => EXIT_CONFIG,
+            Self::Io { .. } => EXIT_IO,
+            Self::Http { .. } => EXIT_NETWORK,
+            Self::Provider { .. } if self.api_kind() == Some(ApiErrorKind::Auth) => EXIT_AUTH,
+            Self::Provider { .. } => EXIT_PROVIDER,
+            Self::IpDiscovery { .. } => EXIT_IP_DISCOVERY,
+            Self::Shutdown { .. } => 0,
+        }
+    }
+}
+
+// The causes are part of the message, as an error is logged in one line.
+impl std::fmt::Display for AppErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (msg, source) = match self {
+            Self::Config { msg, source }
+            | Self::Io { msg, source }
+            | Self::Http { msg, source }
+            | Self::Provider { msg, source, .. }
+            | Self::IpDiscovery { msg, source } => (msg, source),
+            Self::Shutdown { signal } => return write!(f, "received {}, quit", signal),
+        };
+
+        write!(f, "{}", msg)?;
+        if let Some(source) = source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
+    }
 }
 
+impl StdError for AppErr {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        let source = match self {
+            Self::Config { source, .. }
+            | Self::Io { source, .. }
+            | Self::Http { source, .. }
+            | Self::Provider { source, .. }
+            | Self::IpDiscovery { source, .. } => source,
+            Self::Shutdown { .. } => return None,
+        };
+        source.as_deref().map(|s| s as &(dyn StdError + 'static))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiErrorKind {
+    // The credentials are invalid or lack a permission.
+    Auth,
+    // The request was rejected, e.g. a malformed or conflicting record.
+    Validation,
+    RateLimited,
+    NotFound,
+    Server,
+    Other,
+}
+
+// An error reported by a provider API, e.g. parsed from the Cloudflare
+// response envelope.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: u16,
+    // The first error code of the response, if there was any.
+    pub code: Option<i64>,
+    pub message: String,
+    // The id of the request on the provider side (Cloudflare's `cf-ray`),
+    // which their support asks for.
+    pub ray_id: Option<String>,
+}
+
+// Cloudflare error codes, which are more specific than the HTTP status. A
+// 200 response with `success: false` only has the code.
+const CF_AUTH_CODES: &[i64] = &[1000, 6003, 6111, 9103, 9106, 9107, 9109, 10000, 10001];
+const CF_RATE_LIMIT_CODES: &[i64] = &[971, 10429];
+
+impl ApiError {
+    pub fn kind(&self) -> ApiErrorKind {
+        match self.code {
+            Some(code) if CF_AUTH_CODES.contains(&code) => return ApiErrorKind::Auth,
+            Some(code) if CF_RATE_LIMIT_CODES.contains(&code) => return ApiErrorKind::RateLimited,
+            _ => (),
+        }
+
+        match self.status {
+            401 | 403 => ApiErrorKind::Auth,
+            429 => ApiErrorKind::RateLimited,
+            404 => ApiErrorKind::NotFound,
+            400 | 409 | 422 => ApiErrorKind::Validation,
+            500..=599 => ApiErrorKind::Server,
+            // Cloudflare answers 200 to some rejected requests.
+            _ if self.code.is_some() => ApiErrorKind::Validation,
+            _ => ApiErrorKind::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "api error (status {}", self.status)?;
+        if let Some(code) = self.code {
+            write!(f, ", code {}", code)?;
+        }
+        if let Some(ray_id) = &self.ray_id {
+            write!(f, ", ray {}", ray_id)?;
+        }
+        write!(f, "): {}", self.message)
+    }
+}
+
+impl StdError for ApiError {}
+
 impl From<reqwest::Error> for AppErr {
     fn from(value: reqwest::Error) -> Self {
-        Self {
-            msg: value.to_string(),
+        Self::Http {
+            msg: "http request failed".to_string(),
+            source: Some(Box::new(value)),
         }
     }
 }
 
 impl From<std::net::AddrParseError> for AppErr {
     fn from(value: std::net::AddrParseError) -> Self {
-        Self {
-            msg: value.to_string(),
+        Self::IpDiscovery {
+            msg: "invalid ip address".to_string(),
+            source: Some(Box::new(value)),
         }
     }
 }
 
+// JSON is what providers answer with.
 impl From<serde_json::Error> for AppErr {
     fn from(value: serde_json::Error) -> Self {
-        Self {
-            msg: format!("[serde json]: {:?}", value),
+        Self::Provider {
+            msg: "[serde json]".to_string(),
+            api: None,
+            source: Some(Box::new(value)),
         }
     }
 }
 
 impl From<serde_yaml::Error> for AppErr {
     fn from(value: serde_yaml::Error) -> Self {
-        Self {
-            msg: format!("[serde yaml]: {:?}", value),
+        Self::Config {
+            msg: "[serde yaml]".to_string(),
+            source: Some(Box::new(value)),
         }
     }
 }
 
 impl From<std::io::Error> for AppErr {
     fn from(value: std::io::Error) -> Self {
-        Self {
-            msg: format!("[io error]: {:?}", value),
+        Self::Io {
+            msg: "[io error]".to_string(),
+            source: Some(Box::new(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(status: u16, code: Option<i64>) -> ApiErrorKind {
+        ApiError {
+            status,
+            code,
+            message: String::new(),
+            ray_id: None,
         }
+        .kind()
+    }
+
+    #[test]
+    fn codes_take_precedence_over_the_status() {
+        assert_eq!(kind(400, Some(10000)), ApiErrorKind::Auth);
+        assert_eq!(kind(200, Some(9109)), ApiErrorKind::Auth);
+        assert_eq!(kind(403, Some(971)), ApiErrorKind::RateLimited);
+        assert_eq!(kind(400, Some(10429)), ApiErrorKind::RateLimited);
+    }
+
+    #[test]
+    fn status_decides_without_a_known_code() {
+        assert_eq!(kind(401, None), ApiErrorKind::Auth);
+        assert_eq!(kind(403, Some(1234)), ApiErrorKind::Auth);
+        assert_eq!(kind(429, None), ApiErrorKind::RateLimited);
+        assert_eq!(kind(404, Some(7003)), ApiErrorKind::NotFound);
+        assert_eq!(kind(400, Some(81057)), ApiErrorKind::Validation);
+        assert_eq!(kind(409, None), ApiErrorKind::Validation);
+        assert_eq!(kind(422, None), ApiErrorKind::Validation);
+        assert_eq!(kind(500, None), ApiErrorKind::Server);
+        assert_eq!(kind(503, Some(1234)), ApiErrorKind::Server);
+        assert_eq!(kind(200, Some(1004)), ApiErrorKind::Validation);
+        assert_eq!(kind(200, None), ApiErrorKind::Other);
+        assert_eq!(kind(418, None), ApiErrorKind::Other);
+    }
+
+    #[test]
+    fn auth_errors_exit_with_their_own_code() {
+        let auth = ApiError {
+            status: 403,
+            code: None,
+            message: "forbidden".to_string(),
+            ray_id: None,
+        };
+        let other = ApiError {
+            status: 500,
+            ..auth.clone()
+        };
+
+        assert_eq!(AppErr::api(auth).exit_code(), EXIT_AUTH);
+        assert_eq!(AppErr::api(other).exit_code(), EXIT_PROVIDER);
     }
 }
@@ -0,0 +1,82 @@
+use std::net::IpAddr;
+use std::process::Stdio;
+
+use super::{IpFamily, IpLookup};
+use crate::err::*;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::process::Command;
+
+// The first address of the looked up family printed by an external command.
+#[derive(Debug, Deserialize)]
+pub(super) struct CommandSource {
+    // The program followed by its arguments, no shell is involved.
+    command: Vec<String>,
+}
+
+#[async_trait]
+impl IpLookup for CommandSource {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        let (program, args) = self.command.split_first().ok_or(AppErr::ip_discovery(
+            "command ip source has an empty command".to_string(),
+        ))?;
+
+        let mut command = std::process::Command::new(program);
+        command
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        #[cfg(target_os = "linux")]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+        // The child is killed if the source times out and drops this future.
+        let child = Command::from(command).kill_on_drop(true).spawn()?;
+        let mut guard = ProcessGroupGuard(child.id());
+        let output = child.wait_with_output().await?;
+        guard.0 = None;
+
+        if !output.status.success() {
+            return Err(AppErr::ip_discovery(format!(
+                "command exits with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        // Anything that cannot be part of an address literal separates
+        // tokens, e.g. the prefix length of `192.0.2.1/24`.
+        stdout
+            .split(|c: char| !(c.is_ascii_hexdigit() || c == '.' || c == ':'))
+            .filter_map(|token| token.parse::<IpAddr>().ok())
+            .find(|ip| IpFamily::of(ip) == family)
+            .ok_or(AppErr::ip_discovery(format!(
+                "no {} address in the output: {}",
+                family,
+                stdout.trim()
+            )))
+    }
+}
+
+// Kills the whole process group of the child on drop, so that processes it
+// spawned, such as the commands of a shell script, do not outlive a timeout.
+struct ProcessGroupGuard(Option<u32>);
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let Some(pgid) = self.0 {
+            // SAFETY: kill(2) has no memory safety requirements.
+            unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) };
+        }
+    }
+}
+
+impl std::fmt::Display for CommandSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.command.join(" "))
+    }
+}
@@ -0,0 +1,383 @@
+use std::net::{IpAddr, SocketAddr};
+
+use super::{bind_addr, resolve_server, udp_request, IpFamily, IpLookup};
+use crate::err::*;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+const DNS_PORT: u16 = 53;
+const CLASS_IN: u16 = 1;
+// Response bit of the header flags
+const FLAG_QR: u16 = 0x8000;
+// Truncated bit of the header flags
+const FLAG_TC: u16 = 0x0200;
+// Recursion desired bit of the header flags
+const FLAG_RD: u16 = 0x0100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RecordType {
+    A,
+    Aaaa,
+    Txt,
+}
+
+impl RecordType {
+    fn code(&self) -> u16 {
+        match self {
+            Self::A => 1,
+            Self::Aaaa => 28,
+            Self::Txt => 16,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Protocol {
+    // UDP, retried over TCP if the answer is truncated.
+    #[default]
+    Udp,
+    Tcp,
+}
+
+// Ask a DNS server which address the query comes from, e.g.
+// `myip.opendns.com A @resolver1.opendns.com` or
+// `o-o.myaddr.l.google.com TXT @ns1.google.com`.
+#[derive(Debug, Deserialize)]
+pub(super) struct DnsSource {
+    // Host name or address of the server, with an optional port.
+    server: String,
+    name: String,
+    // Defaults to A or AAAA depending on the address family looked up.
+    #[serde(default)]
+    record: Option<RecordType>,
+    #[serde(default)]
+    protocol: Protocol,
+}
+
+impl DnsSource {
+    // The query is retransmitted until an answer with its id comes back.
+    async fn query_udp(&self, server: SocketAddr, id: u16, query: &[u8]) -> Result<Vec<u8>> {
+        let socket = UdpSocket::bind(bind_addr(&server)).await?;
+        udp_request(&socket, server, query, |resp| {
+            resp.len() >= 12
+                && u16::from_be_bytes([resp[0], resp[1]]) == id
+                && u16::from_be_bytes([resp[2], resp[3]]) & FLAG_QR != 0
+        })
+        .await
+    }
+
+    async fn query_tcp(&self, server: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(server).await?;
+
+        stream
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .await?;
+        stream.write_all(query).await?;
+
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0u8; len as usize];
+        stream.read_exact(&mut buf).await?;
+
+        Ok(buf)
+    }
+}
+
+#[async_trait]
+impl IpLookup for DnsSource {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        let record = self.record.unwrap_or(match family {
+            IpFamily::V4 => RecordType::A,
+            IpFamily::V6 => RecordType::Aaaa,
+        });
+
+        let server = resolve_server(&self.server, DNS_PORT, family).await?;
+        let id = fastrand::u16(..);
+        let query = build_query(id, &self.name, record)?;
+
+        let mut resp = match self.protocol {
+            Protocol::Udp => self.query_udp(server, id, &query).await?,
+            Protocol::Tcp => self.query_tcp(server, &query).await?,
+        };
+
+        if self.protocol == Protocol::Udp && resp.len() >= 4 {
+            let flags = u16::from_be_bytes([resp[2], resp[3]]);
+            if flags & FLAG_TC != 0 {
+                resp = self.query_tcp(server, &query).await?;
+            }
+        }
+
+        let answers = parse_answers(id, &resp, record)?;
+
+        answers
+            .into_iter()
+            .find(|ip| IpFamily::of(ip) == family)
+            .ok_or(AppErr::ip_discovery(format!(
+                "no {} address in the answer of {} from {}",
+                family, self.name, self.server
+            )))
+    }
+}
+
+impl std::fmt::Display for DnsSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} @{}", self.name, self.server)
+    }
+}
+
+fn build_query(id: u16, name: &str, record: RecordType) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(512);
+
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RD.to_be_bytes());
+    // qdcount, ancount, nscount, arcount
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(AppErr::ip_discovery(format!("invalid dns name: {}", name)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    query.extend_from_slice(&record.code().to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+fn malformed() -> AppErr {
+    AppErr::ip_discovery("malformed dns response".to_string())
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Result<u16> {
+    buf.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(malformed)
+}
+
+// Returns the position right after the (possibly compressed) name at `pos`.
+fn skip_name(buf: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *buf.get(pos).ok_or_else(malformed)?;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn parse_answers(id: u16, buf: &[u8], record: RecordType) -> Result<Vec<IpAddr>> {
+    if read_u16(buf, 0)? != id {
+        return Err(AppErr::ip_discovery("dns response id mismatch".to_string()));
+    }
+
+    let rcode = read_u16(buf, 2)? & 0x000f;
+    if rcode != 0 {
+        return Err(AppErr::ip_discovery(format!(
+            "dns server answers with rcode {}",
+            rcode
+        )));
+    }
+
+    let qdcount = read_u16(buf, 4)?;
+    let ancount = read_u16(buf, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        // name, qtype and qclass
+        pos = skip_name(buf, pos)? + 4;
+    }
+
+    let mut answers = Vec::new();
+    for _ in 0..ancount {
+        pos = skip_name(buf, pos)?;
+        let rtype = read_u16(buf, pos)?;
+        let rdlength = read_u16(buf, pos + 8)? as usize;
+        pos += 10;
+
+        let rdata = buf.get(pos..pos + rdlength).ok_or_else(malformed)?;
+        pos += rdlength;
+
+        if rtype != record.code() {
+            continue;
+        }
+
+        match record {
+            RecordType::A => {
+                let octets: [u8; 4] = rdata.try_into().map_err(|_| malformed())?;
+                answers.push(IpAddr::from(octets));
+            }
+            RecordType::Aaaa => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| malformed())?;
+                answers.push(IpAddr::from(octets));
+            }
+            RecordType::Txt => {
+                let mut text = Vec::new();
+                let mut i = 0;
+                while i < rdata.len() {
+                    let len = rdata[i] as usize;
+                    text.extend_from_slice(rdata.get(i + 1..i + 1 + len).ok_or_else(malformed)?);
+                    i += 1 + len;
+                }
+                if let Ok(ip) = String::from_utf8_lossy(&text).trim().parse() {
+                    answers.push(ip);
+                }
+            }
+        }
+    }
+
+    Ok(answers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tokio::net::TcpListener;
+
+    // A response to `query` holding one answer of `rtype`, whose name points
+    // back to the question.
+    fn response(query: &[u8], flags: u16, rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut resp = query[..2].to_vec();
+        resp.extend_from_slice(&flags.to_be_bytes());
+        resp.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 0]);
+        resp.extend_from_slice(&query[12..]);
+        resp.extend_from_slice(&[0xc0, 12]);
+        resp.extend_from_slice(&rtype.to_be_bytes());
+        resp.extend_from_slice(&CLASS_IN.to_be_bytes());
+        resp.extend_from_slice(&60u32.to_be_bytes());
+        resp.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        resp.extend_from_slice(rdata);
+        resp
+    }
+
+    fn source(server: SocketAddr, record: Option<RecordType>, protocol: Protocol) -> DnsSource {
+        DnsSource {
+            server: server.to_string(),
+            name: "myip.example.com".to_owned(),
+            record,
+            protocol,
+        }
+    }
+
+    #[test]
+    fn build_query_encodes_the_question() {
+        let query = build_query(0xbeef, "myip.example.com.", RecordType::Aaaa).unwrap();
+        let mut expected = vec![0xbe, 0xef, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x04myip\x07example\x03com\x00");
+        expected.extend_from_slice(&[0, 28, 0, 1]);
+        assert_eq!(query, expected);
+
+        assert!(build_query(1, "a..example.com", RecordType::A).is_err());
+        assert!(build_query(1, &format!("{}.com", "x".repeat(64)), RecordType::A).is_err());
+    }
+
+    #[test]
+    fn parse_answers_reads_each_record_type() {
+        let query = build_query(7, "myip.example.com", RecordType::A).unwrap();
+        let resp = response(&query, 0x8180, 1, &[192, 0, 2, 5]);
+        assert_eq!(
+            parse_answers(7, &resp, RecordType::A).unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 5))]
+        );
+
+        let ip: Ipv6Addr = "2001:db8::5".parse().unwrap();
+        let resp = response(&query, 0x8180, 28, &ip.octets());
+        assert_eq!(
+            parse_answers(7, &resp, RecordType::Aaaa).unwrap(),
+            vec![IpAddr::V6(ip)]
+        );
+
+        // A TXT record of two character strings, joined into one address.
+        let resp = response(&query, 0x8180, 16, b"\x06192.0.\x042.77");
+        assert_eq!(
+            parse_answers(7, &resp, RecordType::Txt).unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 77))]
+        );
+
+        // Answers of another type, e.g. a CNAME, are skipped.
+        let resp = response(&query, 0x8180, 5, b"\x00");
+        assert!(parse_answers(7, &resp, RecordType::A).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_answers_rejects_bad_responses() {
+        let query = build_query(7, "myip.example.com", RecordType::A).unwrap();
+        let resp = response(&query, 0x8180, 1, &[192, 0, 2, 5]);
+
+        assert!(parse_answers(8, &resp, RecordType::A).is_err());
+        assert!(parse_answers(7, &resp[..resp.len() - 1], RecordType::A).is_err());
+
+        // NXDOMAIN
+        let resp = response(&query, 0x8183, 1, &[192, 0, 2, 5]);
+        assert!(parse_answers(7, &resp, RecordType::A).is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_lookup_is_retransmitted_and_skips_foreign_ids() {
+        let stub = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = stub.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            // The first query is lost.
+            stub.recv_from(&mut buf).await.unwrap();
+
+            let (len, from) = stub.recv_from(&mut buf).await.unwrap();
+            let resp = response(&buf[..len], 0x8180, 1, &[192, 0, 2, 5]);
+            let mut stale = resp.clone();
+            stale[0] ^= 0xff;
+            stub.send_to(&stale, from).await.unwrap();
+            stub.send_to(&resp, from).await.unwrap();
+        });
+
+        let ip = source(server, None, Protocol::Udp)
+            .lookup(IpFamily::V4)
+            .await
+            .unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 5)));
+    }
+
+    #[tokio::test]
+    async fn truncated_udp_answer_is_retried_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let stub = UdpSocket::bind(server).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (len, from) = stub.recv_from(&mut buf).await.unwrap();
+            let mut resp = buf[..len].to_vec();
+            resp[2..4].copy_from_slice(&(0x8180 | FLAG_TC).to_be_bytes());
+            stub.send_to(&resp, from).await.unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut query = vec![0u8; len as usize];
+            stream.read_exact(&mut query).await.unwrap();
+
+            let resp = response(&query, 0x8180, 16, b"\x0a192.0.2.99");
+            stream
+                .write_all(&(resp.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&resp).await.unwrap();
+        });
+
+        let ip = source(server, Some(RecordType::Txt), Protocol::Udp)
+            .lookup(IpFamily::V4)
+            .await
+            .unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 99)));
+    }
+}
@@ -0,0 +1,239 @@
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+
+use super::{resolve_server, IpFamily, IpLookup};
+use crate::err::*;
+
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+const TRACE_URL_V4: &str = "https://1.1.1.1/cdn-cgi/trace";
+const TRACE_URL_V6: &str = "https://[2606:4700:4700::1111]/cdn-cgi/trace";
+
+// Requests `url` over `family` only. A dual stack endpoint reports the
+// address it is reached from, so the host is resolved here and pinned to an
+// address of the family being looked up.
+async fn http_get(url: &str, family: IpFamily) -> Result<String> {
+    let parsed =
+        Url::parse(url).map_err(|e| AppErr::ip_discovery(format!("invalid url {}: {}", url, e)))?;
+    let host = parsed
+        .host_str()
+        .ok_or(AppErr::ip_discovery(format!("no host in url {}", url)))?;
+
+    let mut client = reqwest::Client::builder();
+    match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) if IpFamily::of(&ip) != family => {
+            return Err(AppErr::ip_discovery(format!(
+                "{} cannot report an {} address",
+                url, family
+            )));
+        }
+        Ok(_) => {}
+        Err(_) => {
+            let port = parsed.port_or_known_default().unwrap_or(80);
+            let addr = resolve_server(host, port, family).await?;
+            client = client.resolve(host, addr);
+        }
+    }
+
+    let resp = client.build()?.get(url).send().await?;
+
+    let status = resp.status();
+    let body = resp.text().await?;
+
+    if status != 200 {
+        return Err(AppErr::ip_discovery(format!(
+            "Request ip address from {} failed: {}",
+            url, body
+        )));
+    }
+
+    Ok(body)
+}
+
+// Cloudflare's `/cdn-cgi/trace` format, a `key=value` per line.
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct TraceSource {
+    #[serde(default)]
+    url: Option<String>,
+}
+
+impl TraceSource {
+    fn url(&self, family: IpFamily) -> &str {
+        match (&self.url, family) {
+            (Some(url), _) => url,
+            (None, IpFamily::V4) => TRACE_URL_V4,
+            (None, IpFamily::V6) => TRACE_URL_V6,
+        }
+    }
+}
+
+#[async_trait]
+impl IpLookup for TraceSource {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        let body = http_get(self.url(family), family).await?;
+        let reader = BufReader::new(body.as_bytes());
+
+        let ip = reader.lines().map_while(|line| line.ok()).find_map(|line| {
+            if let Some((k, v)) = line.split_once('=') {
+                if k == "ip" {
+                    Some(v.to_owned())
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        });
+
+        let ip = ip.ok_or(AppErr::ip_discovery("cannot get public ip".to_string()))?;
+
+        Ok(ip.parse()?)
+    }
+}
+
+impl std::fmt::Display for TraceSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.url {
+            Some(url) => write!(f, "{}", url),
+            None => write!(f, "default"),
+        }
+    }
+}
+
+// Endpoints answering with the bare address, such as ipify or icanhazip.
+#[derive(Debug, Deserialize)]
+pub(super) struct HttpSource {
+    url: String,
+}
+
+#[async_trait]
+impl IpLookup for HttpSource {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        let body = http_get(&self.url, family).await?;
+        Ok(body.trim().parse()?)
+    }
+}
+
+impl std::fmt::Display for HttpSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+// Endpoints answering with a JSON document. `field` is a dot separated path
+// to the address, array elements are addressed by their index.
+#[derive(Debug, Deserialize)]
+pub(super) struct JsonSource {
+    url: String,
+    field: String,
+}
+
+#[async_trait]
+impl IpLookup for JsonSource {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        let body = http_get(&self.url, family).await?;
+        let json: JsonValue = serde_json::from_str(&body)
+            .map_err(|e| AppErr::ip_discovery(format!("invalid json from {}: {}", self.url, e)))?;
+
+        let value = self
+            .field
+            .split('.')
+            .try_fold(&json, |value, key| match value {
+                JsonValue::Array(array) => key.parse::<usize>().ok().and_then(|i| array.get(i)),
+                _ => value.get(key),
+            })
+            .and_then(|value| value.as_str())
+            .ok_or(AppErr::ip_discovery(format!(
+                "no string field '{}' in {}",
+                self.field,
+                body.trim()
+            )))?;
+
+        Ok(value.trim().parse()?)
+    }
+}
+
+impl std::fmt::Display for JsonSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} [{}]", self.url, self.field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // An endpoint on IPv4 loopback answering every request with the address
+    // it was reached from.
+    async fn echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, peer) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                }
+                let body = peer.ip().to_string();
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn lookups_connect_over_the_requested_family() {
+        let addr = echo().await;
+        let source = HttpSource {
+            url: format!("http://localhost:{}/", addr.port()),
+        };
+
+        let ip = source.lookup(IpFamily::V4).await.unwrap();
+        assert_eq!(ip, "127.0.0.1".parse::<IpAddr>().unwrap());
+
+        // The endpoint is only reachable over IPv4, which must not stand in
+        // for IPv6 whether or not localhost also resolves to ::1.
+        source
+            .lookup(IpFamily::V6)
+            .await
+            .expect_err("reached over IPv4");
+    }
+
+    #[tokio::test]
+    async fn literals_of_the_other_family_are_refused() {
+        let source = TraceSource {
+            url: Some("http://127.0.0.1:9/cdn-cgi/trace".to_string()),
+        };
+        let err = source.lookup(IpFamily::V6).await.unwrap_err();
+        assert!(err.to_string().contains("cannot report an"), "{}", err);
+
+        let source = HttpSource {
+            url: "http://[::1]:9/".to_string(),
+        };
+        let err = source.lookup(IpFamily::V4).await.unwrap_err();
+        assert!(err.to_string().contains("cannot report an"), "{}", err);
+    }
+}
@@ -0,0 +1,210 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::{is_cgnat, IpFamily, IpLookup};
+use crate::err::*;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+// Flags of /proc/net/if_inet6, see include/uapi/linux/if_addr.h
+const IFA_F_TEMPORARY: u32 = 0x01;
+const IFA_F_DEPRECATED: u32 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Scope {
+    Global,
+    // RFC 1918 and the shared address space 100.64.0.0/10.
+    Private,
+    // IPv6 unique local addresses, fc00::/7.
+    Ula,
+    LinkLocal,
+}
+
+impl Scope {
+    fn of(ip: &IpAddr) -> Option<Self> {
+        match ip {
+            IpAddr::V4(ip) => {
+                if ip.is_unspecified() || ip.is_loopback() || ip.is_broadcast() || ip.is_multicast()
+                {
+                    None
+                } else if ip.is_link_local() {
+                    Some(Self::LinkLocal)
+                } else if ip.is_private() || is_cgnat(ip) {
+                    Some(Self::Private)
+                } else {
+                    Some(Self::Global)
+                }
+            }
+            IpAddr::V6(ip) => {
+                let first = ip.segments()[0];
+                if ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() {
+                    None
+                } else if first & 0xffc0 == 0xfe80 {
+                    Some(Self::LinkLocal)
+                } else if first & 0xfe00 == 0xfc00 {
+                    Some(Self::Ula)
+                } else {
+                    Some(Self::Global)
+                }
+            }
+        }
+    }
+}
+
+fn default_scopes() -> Vec<Scope> {
+    vec![Scope::Global]
+}
+
+// Addresses assigned to a local network interface. Only Linux is supported.
+#[derive(Debug, Deserialize)]
+pub(super) struct InterfaceSource {
+    name: String,
+    // Scopes an address may have to be accepted, earlier ones are preferred.
+    #[serde(default = "default_scopes")]
+    scopes: Vec<Scope>,
+    // Accept IPv6 temporary (privacy extension) addresses.
+    #[serde(default)]
+    allow_temporary: bool,
+    // Accept IPv6 addresses whose preferred lifetime has expired.
+    #[serde(default)]
+    allow_deprecated: bool,
+}
+
+#[derive(Debug)]
+struct IfAddr {
+    ip: IpAddr,
+    temporary: bool,
+    deprecated: bool,
+}
+
+#[async_trait]
+impl IpLookup for InterfaceSource {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        let mut candidates: Vec<(usize, IfAddr)> = if_addrs(&self.name, family)?
+            .into_iter()
+            .filter(|addr| self.allow_temporary || !addr.temporary)
+            .filter(|addr| self.allow_deprecated || !addr.deprecated)
+            .filter_map(|addr| {
+                let scope = Scope::of(&addr.ip)?;
+                let rank = self.scopes.iter().position(|s| *s == scope)?;
+                Some((rank, addr))
+            })
+            .collect();
+
+        // Stable addresses are preferred over deprecated and temporary ones.
+        candidates.sort_by_key(|(rank, addr)| (*rank, addr.deprecated, addr.temporary));
+
+        candidates
+            .into_iter()
+            .next()
+            .map(|(_, addr)| addr.ip)
+            .ok_or(AppErr::ip_discovery(format!(
+                "interface {} has no {} address in scopes {:?}",
+                self.name, family, self.scopes
+            )))
+    }
+}
+
+impl std::fmt::Display for InterfaceSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn if_addrs(name: &str, family: IpFamily) -> Result<Vec<IfAddr>> {
+    if !std::path::Path::new("/sys/class/net").join(name).exists() {
+        return Err(AppErr::ip_discovery(format!(
+            "no such network interface: {}",
+            name
+        )));
+    }
+
+    match family {
+        IpFamily::V4 => Ok(if_addrs_v4(name)?
+            .into_iter()
+            .map(|ip| IfAddr {
+                ip: IpAddr::V4(ip),
+                temporary: false,
+                deprecated: false,
+            })
+            .collect()),
+        IpFamily::V6 => if_addrs_v6(name),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn if_addrs(_name: &str, _family: IpFamily) -> Result<Vec<IfAddr>> {
+    Err(AppErr::ip_discovery(
+        "interface ip source is only supported on Linux".to_string(),
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn if_addrs_v4(name: &str) -> Result<Vec<Ipv4Addr>> {
+    use std::ffi::CStr;
+
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: `ifap` is a valid out pointer, the list is freed below.
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut addrs = Vec::new();
+    let mut cur = ifap;
+    while !cur.is_null() {
+        // SAFETY: `cur` is a node of the list returned by getifaddrs.
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+
+        // SAFETY: `ifa_name` is a NUL terminated string owned by the list.
+        let ifname = unsafe { CStr::from_ptr(ifa.ifa_name) };
+        // SAFETY: `ifa_addr` is checked to be non-null above.
+        let sa_family = unsafe { (*ifa.ifa_addr).sa_family };
+        if ifname.to_bytes() != name.as_bytes() || i32::from(sa_family) != libc::AF_INET {
+            continue;
+        }
+
+        // SAFETY: an AF_INET address is always a sockaddr_in.
+        let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+        addrs.push(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)));
+    }
+
+    // SAFETY: `ifap` was returned by getifaddrs and is not used afterwards.
+    unsafe { libc::freeifaddrs(ifap) };
+
+    Ok(addrs)
+}
+
+// Each line of /proc/net/if_inet6 is:
+// address ifindex prefix_len scope flags ifname
+#[cfg(target_os = "linux")]
+fn if_addrs_v6(name: &str) -> Result<Vec<IfAddr>> {
+    let content = std::fs::read_to_string("/proc/net/if_inet6")?;
+
+    let addrs = content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 6 || fields[5] != name {
+                return None;
+            }
+
+            let ip = u128::from_str_radix(fields[0], 16).ok()?;
+            let flags = u32::from_str_radix(fields[4], 16).ok()?;
+
+            Some(IfAddr {
+                ip: IpAddr::V6(Ipv6Addr::from(ip)),
+                temporary: flags & IFA_F_TEMPORARY != 0,
+                deprecated: flags & IFA_F_DEPRECATED != 0,
+            })
+        })
+        .collect();
+
+    Ok(addrs)
+}
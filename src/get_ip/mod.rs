@@ -0,0 +1,567 @@
+mod command;
+mod dns;
+mod http;
+mod interface;
+mod prefix;
+mod router;
+mod stun;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use crate::err::*;
+use command::*;
+use dns::*;
+use http::*;
+use interface::*;
+pub(crate) use prefix::PrefixHost;
+use router::*;
+use stun::*;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+// Name of the source configured by the top level `ip_discovery`, used by
+// records that do not name one.
+pub(crate) const DEFAULT_SOURCE: &str = "default";
+
+// UDP requests are retransmitted until the source times out.
+const RETRANSMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self::V4,
+            IpAddr::V6(_) => Self::V6,
+        }
+    }
+}
+
+impl std::fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::V4 => write!(f, "IPv4"),
+            Self::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+// The public addresses resolved in one sync cycle. A family is `None` if it
+// was not requested or could not be obtained.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct PubAddrs {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+}
+
+impl PubAddrs {
+    pub fn get(&self, family: IpFamily) -> Option<IpAddr> {
+        match family {
+            IpFamily::V4 => self.v4.map(IpAddr::V4),
+            IpFamily::V6 => self.v6.map(IpAddr::V6),
+        }
+    }
+}
+
+// The public addresses of every named source needed in one sync cycle.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct ResolvedAddrs(HashMap<String, PubAddrs>);
+
+impl ResolvedAddrs {
+    pub fn get(&self, source: &str, family: IpFamily) -> Option<IpAddr> {
+        self.0.get(source).and_then(|addrs| addrs.get(family))
+    }
+}
+
+impl FromIterator<(String, PubAddrs)> for ResolvedAddrs {
+    fn from_iter<I: IntoIterator<Item = (String, PubAddrs)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+// Resolves `server`, a host name or address with an optional port. Servers
+// that report our address back are contacted over the family being looked
+// up, since that is the address they will see.
+async fn resolve_server(server: &str, default_port: u16, family: IpFamily) -> Result<SocketAddr> {
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+
+    let host = if server.contains(':') {
+        server.to_owned()
+    } else {
+        format!("{}:{}", server, default_port)
+    };
+
+    tokio::net::lookup_host(host)
+        .await?
+        .find(|addr| IpFamily::of(&addr.ip()) == family)
+        .ok_or(AppErr::ip_discovery(format!(
+            "server {} has no {} address",
+            server, family
+        )))
+}
+
+// The shared address space 100.64.0.0/10 (RFC 6598) used by carrier-grade NAT.
+fn is_cgnat(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[0] == 100 && octets[1] & 0xc0 == 64
+}
+
+// What to do with an address that cannot be reached from the internet, e.g.
+// one behind a carrier-grade NAT.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NatPolicy {
+    // Log a warning and publish the address anyway.
+    #[default]
+    Warn,
+    // Fail the lookup, so the address is never published.
+    Refuse,
+}
+
+impl NatPolicy {
+    fn apply(self, source: &dyn std::fmt::Display, reason: String) -> Result<()> {
+        match self {
+            Self::Warn => {
+                warn!("{}: {}", source, reason);
+                Ok(())
+            }
+            Self::Refuse => Err(AppErr::ip_discovery(format!(
+                "{} refuses to publish: {}",
+                source, reason
+            ))),
+        }
+    }
+}
+
+// The local address the routing table picks to reach `peer`. Connecting a UDP
+// socket sends nothing.
+async fn local_ip(peer: SocketAddr) -> Result<IpAddr> {
+    let socket = UdpSocket::bind(bind_addr(&peer)).await?;
+    socket.connect(peer).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+// The unspecified address of the same family as `peer`, for binding sockets.
+fn bind_addr(peer: &SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+// Sends `request` to `server` until a datagram from it passes `is_reply`.
+async fn udp_request<F>(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    request: &[u8],
+    is_reply: F,
+) -> Result<Vec<u8>>
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let mut buf = vec![0u8; 1500];
+    loop {
+        socket.send_to(request, server).await?;
+
+        let (len, from) =
+            match tokio::time::timeout(RETRANSMIT_INTERVAL, socket.recv_from(&mut buf)).await {
+                Ok(received) => received?,
+                Err(_) => continue,
+            };
+
+        if from == server && is_reply(&buf[..len]) {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+#[async_trait]
+trait IpLookup {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr>;
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum IpSourceKind {
+    Trace(TraceSource),
+    Http(HttpSource),
+    Json(JsonSource),
+    Interface(InterfaceSource),
+    Dns(DnsSource),
+    Stun(StunSource),
+    Router(RouterSource),
+    Command(CommandSource),
+}
+
+#[async_trait]
+impl IpLookup for IpSourceKind {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        match self {
+            Self::Trace(source) => source.lookup(family).await,
+            Self::Http(source) => source.lookup(family).await,
+            Self::Json(source) => source.lookup(family).await,
+            Self::Interface(source) => source.lookup(family).await,
+            Self::Dns(source) => source.lookup(family).await,
+            Self::Stun(source) => source.lookup(family).await,
+            Self::Router(source) => source.lookup(family).await,
+            Self::Command(source) => source.lookup(family).await,
+        }
+    }
+}
+
+impl std::fmt::Display for IpSourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Trace(source) => write!(f, "trace({})", source),
+            Self::Http(source) => write!(f, "http({})", source),
+            Self::Json(source) => write!(f, "json({})", source),
+            Self::Interface(source) => write!(f, "interface({})", source),
+            Self::Dns(source) => write!(f, "dns({})", source),
+            Self::Stun(source) => write!(f, "stun({})", source),
+            Self::Router(source) => write!(f, "router({})", source),
+            Self::Command(source) => write!(f, "command({})", source),
+        }
+    }
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct IpSource {
+    #[serde(flatten)]
+    kind: IpSourceKind,
+    // Seconds to wait for this source before giving up on it.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+}
+
+impl IpSource {
+    fn trace() -> Self {
+        Self {
+            kind: IpSourceKind::Trace(TraceSource::default()),
+            timeout: default_timeout(),
+        }
+    }
+
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        let timeout = Duration::from_secs(self.timeout);
+        let ip = tokio::time::timeout(timeout, self.kind.lookup(family))
+            .await
+            .map_err(|_| {
+                AppErr::ip_discovery(format!("{} timed out after {}s", self.kind, self.timeout))
+            })??;
+
+        if IpFamily::of(&ip) != family {
+            return Err(AppErr::ip_discovery(format!(
+                "{} returned {}, expect an {} address",
+                self.kind, ip, family
+            )));
+        }
+
+        debug!("{} reports {}", self.kind, ip);
+        Ok(ip)
+    }
+}
+
+fn default_sources() -> Vec<IpSource> {
+    vec![IpSource::trace()]
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Strategy {
+    // Sources are tried in order, the first answer wins.
+    #[default]
+    FirstSuccess,
+    // All sources are queried, more than half of them must agree.
+    Majority,
+    // All sources are queried, every one of them must agree.
+    AllAgree,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct IpDiscovery {
+    #[serde(default)]
+    strategy: Strategy,
+    // A family which is not listed falls back to the trace endpoint for
+    // `ip_discovery`, a named source does not provide it.
+    #[serde(default)]
+    v4: Option<Vec<IpSource>>,
+    #[serde(default)]
+    v6: Option<Vec<IpSource>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct IpSources(HashMap<String, IpDiscovery>);
+
+impl IpSources {
+    pub fn new(mut default: IpDiscovery, mut named: HashMap<String, IpDiscovery>) -> Result<Self> {
+        if named.contains_key(DEFAULT_SOURCE) {
+            return Err(AppErr::config(format!(
+                "ip source '{}' is reserved for 'ip_discovery'",
+                DEFAULT_SOURCE
+            )));
+        }
+
+        default.v4.get_or_insert_with(default_sources);
+        default.v6.get_or_insert_with(default_sources);
+
+        named.insert(DEFAULT_SOURCE.to_owned(), default);
+        Ok(Self(named))
+    }
+
+    pub fn contains(&self, source: &str) -> bool {
+        self.0.contains_key(source)
+    }
+
+    // Whether `source` has any source configured for `family`.
+    pub fn provides(&self, source: &str, family: IpFamily) -> bool {
+        self.0
+            .get(source)
+            .is_some_and(|discovery| !discovery.sources(family).is_empty())
+    }
+
+    // Resolves each source once, for the families wanted from it. A source
+    // that fails is left out, the records following it fail on their own.
+    pub async fn resolve(&self, wanted: &[(String, IpFamily)]) -> ResolvedAddrs {
+        let mut families: HashMap<&str, (bool, bool)> = HashMap::new();
+        for (source, family) in wanted.iter() {
+            let entry = families.entry(source.as_str()).or_default();
+            match family {
+                IpFamily::V4 => entry.0 = true,
+                IpFamily::V6 => entry.1 = true,
+            }
+        }
+
+        let lookups = families
+            .into_iter()
+            .map(|(name, (want_v4, want_v6))| async move {
+                let addrs = match self.0.get(name) {
+                    Some(source) => source.get_pub_addrs(want_v4, want_v6).await,
+                    None => Err(AppErr::ip_discovery(format!(
+                        "unknown ip source '{}'",
+                        name
+                    ))),
+                };
+                (name, addrs)
+            });
+
+        let mut resolved = ResolvedAddrs::default();
+        for (name, addrs) in futures::future::join_all(lookups).await.into_iter() {
+            match addrs {
+                Ok(addrs) => {
+                    debug!("ip source '{}': {:?}", name, addrs);
+                    resolved.0.insert(name.to_owned(), addrs);
+                }
+                Err(e) => warn!("ip source '{}' failed: {}", name, e),
+            }
+        }
+
+        resolved
+    }
+}
+
+impl IpDiscovery {
+    fn sources(&self, family: IpFamily) -> &[IpSource] {
+        let sources = match family {
+            IpFamily::V4 => &self.v4,
+            IpFamily::V6 => &self.v6,
+        };
+        sources.as_deref().unwrap_or_default()
+    }
+
+    async fn get_pub_addrs(&self, want_v4: bool, want_v6: bool) -> Result<PubAddrs> {
+        let mut addrs = PubAddrs::default();
+        let mut failures = Vec::new();
+
+        if want_v4 {
+            match self.resolve(IpFamily::V4).await {
+                Ok(IpAddr::V4(ip)) => addrs.v4 = Some(ip),
+                Ok(_) => unreachable!("family is checked by IpSource::lookup"),
+                Err(e) => {
+                    warn!("cannot get public IPv4 address: {}", e);
+                    failures.push(e.to_string());
+                }
+            }
+        }
+
+        if want_v6 {
+            match self.resolve(IpFamily::V6).await {
+                Ok(IpAddr::V6(ip)) => addrs.v6 = Some(ip),
+                Ok(_) => unreachable!("family is checked by IpSource::lookup"),
+                Err(e) => {
+                    warn!("cannot get public IPv6 address: {}", e);
+                    failures.push(e.to_string());
+                }
+            }
+        }
+
+        if addrs.v4.is_none() && addrs.v6.is_none() && !failures.is_empty() {
+            return Err(AppErr::ip_discovery(format!(
+                "cannot get any public ip: {}",
+                failures.join("; ")
+            )));
+        }
+
+        Ok(addrs)
+    }
+
+    async fn resolve(&self, family: IpFamily) -> Result<IpAddr> {
+        let sources = self.sources(family);
+
+        if sources.is_empty() {
+            return Err(AppErr::ip_discovery(format!(
+                "no {} source is configured",
+                family
+            )));
+        }
+
+        let required = match self.strategy {
+            Strategy::FirstSuccess => {
+                for source in sources.iter() {
+                    match source.lookup(family).await {
+                        Ok(ip) => return Ok(ip),
+                        Err(e) => warn!("{}", e),
+                    }
+                }
+                return Err(AppErr::ip_discovery(format!(
+                    "all {} sources failed",
+                    family
+                )));
+            }
+            Strategy::Majority => sources.len() / 2 + 1,
+            Strategy::AllAgree => sources.len(),
+        };
+
+        let results =
+            futures::future::join_all(sources.iter().map(|source| source.lookup(family))).await;
+
+        let mut votes: Vec<(IpAddr, usize)> = Vec::new();
+        for result in results.into_iter() {
+            match result {
+                Ok(ip) => match votes.iter_mut().find(|(voted, _)| *voted == ip) {
+                    Some((_, count)) => *count += 1,
+                    None => votes.push((ip, 1)),
+                },
+                Err(e) => warn!("{}", e),
+            }
+        }
+
+        match votes.iter().max_by_key(|(_, count)| *count) {
+            Some((ip, count)) if *count >= required => Ok(*ip),
+            _ => Err(AppErr::ip_discovery(format!(
+                "{} sources disagree, {} of {} votes required: {:?}",
+                family,
+                required,
+                sources.len(),
+                votes
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "192.0.2.1";
+    const B: &str = "192.0.2.2";
+
+    // IPv4 sources printing the given addresses, `None` is one that fails.
+    fn discovery(strategy: &str, answers: &[Option<&str>]) -> IpDiscovery {
+        let sources: Vec<String> = answers
+            .iter()
+            .map(|answer| match answer {
+                Some(ip) => format!("{{type: command, command: [echo, '{}']}}", ip),
+                None => "{type: command, command: ['false']}".to_string(),
+            })
+            .collect();
+        let yaml = format!("{{strategy: {}, v4: [{}]}}", strategy, sources.join(", "));
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    async fn resolve(strategy: &str, answers: &[Option<&str>]) -> Result<IpAddr> {
+        discovery(strategy, answers).resolve(IpFamily::V4).await
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn first_success_takes_the_first_answer() {
+        assert_eq!(
+            resolve("first_success", &[None, Some(A), Some(B)])
+                .await
+                .unwrap(),
+            ip(A)
+        );
+        assert_eq!(
+            resolve("first_success", &[Some(B), Some(A)]).await.unwrap(),
+            ip(B)
+        );
+        resolve("first_success", &[None, None]).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn majority_needs_more_than_half_of_the_sources() {
+        assert_eq!(
+            resolve("majority", &[Some(A), Some(B), Some(A)])
+                .await
+                .unwrap(),
+            ip(A)
+        );
+        // Failed sources count as votes against.
+        assert_eq!(
+            resolve("majority", &[Some(A), None, Some(A)])
+                .await
+                .unwrap(),
+            ip(A)
+        );
+        resolve("majority", &[Some(A), None, None])
+            .await
+            .unwrap_err();
+
+        let err = resolve("majority", &[Some(A), Some(B)]).await.unwrap_err();
+        assert!(err.to_string().contains("disagree"), "{}", err);
+        resolve("majority", &[Some(A), Some(B), Some(A), Some(B)])
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn all_agree_needs_every_source() {
+        assert_eq!(
+            resolve("all_agree", &[Some(A), Some(A)]).await.unwrap(),
+            ip(A)
+        );
+        resolve("all_agree", &[Some(A), Some(A), None])
+            .await
+            .unwrap_err();
+        resolve("all_agree", &[Some(A), Some(B)]).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn family_without_sources_fails() {
+        let discovery: IpDiscovery = serde_yaml::from_str("{v4: []}").unwrap();
+        let err = discovery.resolve(IpFamily::V4).await.unwrap_err();
+        assert!(err.to_string().contains("no IPv4 source"), "{}", err);
+    }
+}
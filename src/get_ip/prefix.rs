@@ -0,0 +1,150 @@
+use std::net::Ipv6Addr;
+
+use crate::err::*;
+
+use serde::{Deserialize, Serialize};
+
+// A host inside a delegated IPv6 prefix. The prefix is taken from the first
+// `len` bits of a discovered address, the rest comes from `host` and `mac`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PrefixHost {
+    len: u8,
+    // Bits after the prefix, e.g. `::1:2:3` or `0:0:0:12::5` for the host
+    // `::5` in subnet `12` of a /56.
+    #[serde(default)]
+    host: Option<Ipv6Addr>,
+    // Fills the lower 64 bits with the modified EUI-64 interface id of this
+    // MAC address, as SLAAC does.
+    #[serde(default)]
+    mac: Option<String>,
+}
+
+impl PrefixHost {
+    pub fn validate(&self) -> Result<()> {
+        let max_len = if self.mac.is_some() { 64 } else { 128 };
+        if self.len > max_len {
+            return Err(AppErr::config(format!(
+                "prefix length {} is longer than {}",
+                self.len, max_len
+            )));
+        }
+
+        if self.host.is_none() && self.mac.is_none() {
+            return Err(AppErr::config(
+                "prefix host needs 'host' or 'mac'".to_string(),
+            ));
+        }
+
+        self.interface_id()?;
+        Ok(())
+    }
+
+    fn interface_id(&self) -> Result<u128> {
+        let host = self.host.map(u128::from).unwrap_or_default();
+        let eui64 = match &self.mac {
+            Some(mac) => eui64(mac)?,
+            None => 0,
+        };
+        Ok(host | eui64)
+    }
+
+    // Replaces everything but the prefix of `ip` with the configured host.
+    pub fn apply(&self, ip: Ipv6Addr) -> Result<Ipv6Addr> {
+        let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+        let addr = (u128::from(ip) & mask) | (self.interface_id()? & !mask);
+        Ok(Ipv6Addr::from(addr))
+    }
+}
+
+fn eui64(mac: &str) -> Result<u128> {
+    let octets = mac
+        .split([':', '-'])
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .ok()
+        .filter(|octets| octets.len() == 6)
+        .ok_or(AppErr::config(format!("invalid mac address: {}", mac)))?;
+
+    let id = [
+        octets[0] ^ 0x02,
+        octets[1],
+        octets[2],
+        0xff,
+        0xfe,
+        octets[3],
+        octets[4],
+        octets[5],
+    ];
+
+    Ok(u64::from_be_bytes(id) as u128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix_host(yaml: &str) -> PrefixHost {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn ip(s: &str) -> Ipv6Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn host_is_put_after_the_prefix() {
+        let host = prefix_host("{len: 56, host: '::1:2:3'}");
+        host.validate().unwrap();
+
+        let addr = host.apply(ip("2001:db8:aa:bbcc:dead:beef:1:1")).unwrap();
+        assert_eq!(addr, ip("2001:db8:aa:bb00::1:2:3"));
+    }
+
+    #[test]
+    fn prefix_of_no_bits_and_of_all_bits() {
+        let discovered = ip("2001:db8::dead:beef");
+
+        let host = prefix_host("{len: 0, host: '2001:db8:1::5'}");
+        assert_eq!(host.apply(discovered).unwrap(), ip("2001:db8:1::5"));
+
+        let host = prefix_host("{len: 128, host: '::5'}");
+        assert_eq!(host.apply(discovered).unwrap(), discovered);
+    }
+
+    #[test]
+    fn mac_fills_the_interface_id() {
+        assert_eq!(eui64("00:11:22:33:44:55").unwrap(), 0x0211_22ff_fe33_4455);
+        assert_eq!(eui64("00-11-22-33-44-55").unwrap(), 0x0211_22ff_fe33_4455);
+
+        let host = prefix_host("{len: 64, mac: '00:11:22:33:44:55'}");
+        let addr = host.apply(ip("2001:db8:0:1::99")).unwrap();
+        assert_eq!(addr, ip("2001:db8:0:1:211:22ff:fe33:4455"));
+    }
+
+    #[test]
+    fn invalid_hosts_are_rejected() {
+        let host = prefix_host("{len: 72, mac: '00:11:22:33:44:55'}");
+        let err = host.validate().unwrap_err();
+        assert!(err.to_string().contains("longer than 64"), "{}", err);
+
+        for mac in [
+            "00:11:22:33:44",
+            "00:11:22:33:44:55:66",
+            "00:11:22:33:44:zz",
+            "",
+        ] {
+            let host = PrefixHost {
+                len: 64,
+                host: None,
+                mac: Some(mac.to_string()),
+            };
+            let err = host.validate().unwrap_err();
+            assert!(err.to_string().contains("invalid mac"), "{}: {}", mac, err);
+        }
+
+        let host = prefix_host("{len: 129, host: '::1'}");
+        host.validate().unwrap_err();
+        let host = prefix_host("{len: 64}");
+        host.validate().unwrap_err();
+    }
+}
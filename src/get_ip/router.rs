@@ -0,0 +1,593 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use super::{
+    bind_addr, is_cgnat, resolve_server, udp_request, IpFamily, IpLookup, NatPolicy,
+    RETRANSMIT_INTERVAL,
+};
+use crate::err::*;
+
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const SSDP_PORT: u16 = 1900;
+const IGD_SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+// Services of an IGD which answer GetExternalIPAddress.
+const WAN_SERVICES: [&str; 2] = ["WANIPConnection", "WANPPPConnection"];
+
+// NAT-PMP (RFC 6886) and PCP (RFC 6887) share the same server port.
+const NATPMP_PORT: u16 = 5351;
+const PCP_VERSION: u8 = 2;
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_RESPONSE_BIT: u8 = 0x80;
+const IPPROTO_UDP: u8 = 17;
+// Lifetime in seconds of the throwaway mapping used to learn the address.
+const PCP_MAP_LIFETIME: u32 = 30;
+// How long to wait for the deletion of that mapping to be confirmed.
+const PCP_DELETE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RouterProtocol {
+    #[default]
+    Upnp,
+    Natpmp,
+    Pcp,
+}
+
+// The WAN address as known by the home router.
+#[derive(Debug, Deserialize)]
+pub(super) struct RouterSource {
+    #[serde(default)]
+    protocol: RouterProtocol,
+    // The router, with an optional port. For UPnP the SSDP search is sent to
+    // it instead of the multicast group. For NAT-PMP and PCP it defaults to
+    // the gateway of the IPv4 default route.
+    #[serde(default)]
+    gateway: Option<String>,
+    // UPnP only, the device description URL of the IGD, skips SSDP.
+    #[serde(default)]
+    location: Option<String>,
+    // What to do if the WAN address is a carrier-grade NAT (100.64.0.0/10)
+    // one, the router itself is then not reachable from the internet.
+    #[serde(default)]
+    nat_policy: NatPolicy,
+}
+
+impl RouterSource {
+    async fn gateway(&self, family: IpFamily) -> Result<SocketAddr> {
+        match &self.gateway {
+            Some(gateway) => resolve_server(gateway, NATPMP_PORT, family).await,
+            None if family == IpFamily::V4 => {
+                Ok(SocketAddr::new(default_gateway_v4()?.into(), NATPMP_PORT))
+            }
+            None => Err(AppErr::ip_discovery(
+                "gateway must be configured to look up an IPv6 address".to_string(),
+            )),
+        }
+    }
+
+    async fn upnp(&self) -> Result<IpAddr> {
+        let location = match &self.location {
+            Some(location) => location.clone(),
+            None => self.ssdp_search().await?,
+        };
+        let location = Url::parse(&location).map_err(|e| {
+            AppErr::ip_discovery(format!("invalid IGD location {}: {}", location, e))
+        })?;
+
+        let resp = reqwest::get(location.clone()).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(AppErr::ip_discovery(format!(
+                "cannot fetch IGD description {}: {}",
+                location, status
+            )));
+        }
+        let description = resp.text().await?;
+        let (service_type, control_url) =
+            find_wan_service(&description).ok_or(AppErr::ip_discovery(format!(
+                "no WAN connection service in IGD description {}",
+                location
+            )))?;
+        let control_url = location.join(&control_url).map_err(|e| {
+            AppErr::ip_discovery(format!("invalid IGD control url {}: {}", control_url, e))
+        })?;
+
+        let body = format!(
+            concat!(
+                r#"<?xml version="1.0"?>"#,
+                r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" "#,
+                r#"s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">"#,
+                r#"<s:Body><u:GetExternalIPAddress xmlns:u="{}"/></s:Body>"#,
+                r#"</s:Envelope>"#
+            ),
+            service_type
+        );
+
+        let resp = reqwest::Client::new()
+            .post(control_url)
+            .header("Content-Type", r#"text/xml; charset="utf-8""#)
+            .header(
+                "SOAPAction",
+                format!(r#""{}#GetExternalIPAddress""#, service_type),
+            )
+            .body(body)
+            .send()
+            .await?;
+
+        let status = resp.status();
+        let body = resp.text().await?;
+        if status != 200 {
+            return Err(AppErr::ip_discovery(format!(
+                "GetExternalIPAddress failed: {}",
+                body
+            )));
+        }
+
+        let ip = xml_text(&body, "NewExternalIPAddress").ok_or(AppErr::ip_discovery(format!(
+            "no NewExternalIPAddress in {}",
+            body
+        )))?;
+
+        Ok(ip.trim().parse()?)
+    }
+
+    // Returns the LOCATION of the first IGD answering the M-SEARCH.
+    async fn ssdp_search(&self) -> Result<String> {
+        let target = match &self.gateway {
+            Some(gateway) => resolve_server(gateway, SSDP_PORT, IpFamily::V4).await?,
+            None => SSDP_ADDR.parse().expect("valid SSDP address"),
+        };
+
+        let request = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\n\r\n",
+            SSDP_ADDR, IGD_SEARCH_TARGET
+        );
+
+        // Answers to a multicast search come from the devices themselves, so
+        // the source address is not checked.
+        let socket = UdpSocket::bind(bind_addr(&target)).await?;
+        let mut buf = vec![0u8; 2048];
+        loop {
+            socket.send_to(request.as_bytes(), target).await?;
+
+            let len = match tokio::time::timeout(RETRANSMIT_INTERVAL, socket.recv(&mut buf)).await {
+                Ok(received) => received?,
+                Err(_) => continue,
+            };
+
+            let resp = String::from_utf8_lossy(&buf[..len]);
+            let location = resp.lines().find_map(|line| {
+                let (k, v) = line.split_once(':')?;
+                k.trim()
+                    .eq_ignore_ascii_case("location")
+                    .then(|| v.trim().to_owned())
+            });
+
+            if let Some(location) = location {
+                return Ok(location);
+            }
+        }
+    }
+
+    async fn natpmp(&self) -> Result<IpAddr> {
+        let gateway = self.gateway(IpFamily::V4).await?;
+        let socket = UdpSocket::bind(bind_addr(&gateway)).await?;
+
+        // version 0, opcode 0: external address request
+        let resp = udp_request(&socket, gateway, &[0, 0], |resp| {
+            resp.len() >= 12 && resp[0] == 0 && resp[1] == 128
+        })
+        .await?;
+
+        let result = u16::from_be_bytes([resp[2], resp[3]]);
+        if result != 0 {
+            return Err(AppErr::ip_discovery(format!(
+                "NAT-PMP request failed with result code {}",
+                result
+            )));
+        }
+
+        let octets: [u8; 4] = resp[8..12].try_into().expect("checked length");
+        Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+    }
+
+    // PCP has no plain address query, a short lived MAP is requested and the
+    // assigned external address is taken from the answer.
+    async fn pcp(&self, family: IpFamily) -> Result<IpAddr> {
+        let gateway = self.gateway(family).await?;
+        let socket = UdpSocket::bind(bind_addr(&gateway)).await?;
+        socket.connect(gateway).await?;
+        let local = socket.local_addr()?;
+
+        let mut nonce = [0u8; 12];
+        nonce.iter_mut().for_each(|b| *b = fastrand::u8(..));
+
+        let suggested = match family {
+            IpFamily::V4 => Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+            IpFamily::V6 => Ipv6Addr::UNSPECIFIED,
+        };
+
+        let request = |lifetime: u32| {
+            let mut request = Vec::with_capacity(60);
+            request.extend_from_slice(&[PCP_VERSION, PCP_OPCODE_MAP, 0, 0]);
+            request.extend_from_slice(&lifetime.to_be_bytes());
+            request.extend_from_slice(&to_pcp_addr(local.ip()).octets());
+            request.extend_from_slice(&nonce);
+            request.extend_from_slice(&[IPPROTO_UDP, 0, 0, 0]);
+            request.extend_from_slice(&local.port().to_be_bytes());
+            request.extend_from_slice(&local.port().to_be_bytes());
+            request.extend_from_slice(&suggested.octets());
+            request
+        };
+        let is_reply = |resp: &[u8]| {
+            resp.len() >= 60
+                && resp[0] == PCP_VERSION
+                && resp[1] == PCP_RESPONSE_BIT | PCP_OPCODE_MAP
+                && resp[24..36] == nonce
+        };
+
+        let resp = udp_request(&socket, gateway, &request(PCP_MAP_LIFETIME), is_reply).await?;
+
+        let result = resp[3];
+        if result != 0 {
+            return Err(AppErr::ip_discovery(format!(
+                "PCP MAP request failed with result code {}",
+                result
+            )));
+        }
+
+        // The same MAP with a lifetime of 0 deletes the mapping. The address
+        // is known by now, so a router which does not answer is only logged.
+        let delete = request(0);
+        let delete = udp_request(&socket, gateway, &delete, is_reply);
+        match tokio::time::timeout(PCP_DELETE_TIMEOUT, delete).await {
+            Ok(Ok(resp)) if resp[3] == 0 => {}
+            Ok(Ok(resp)) => warn!("PCP mapping not deleted, result code {}", resp[3]),
+            Ok(Err(e)) => warn!("PCP mapping not deleted: {}", e),
+            Err(_) => warn!("PCP mapping not deleted: {} does not answer", gateway),
+        }
+
+        let octets: [u8; 16] = resp[44..60].try_into().expect("checked length");
+        let ip = Ipv6Addr::from(octets);
+
+        Ok(match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        })
+    }
+}
+
+#[async_trait]
+impl IpLookup for RouterSource {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        let ip = match (self.protocol, family) {
+            (RouterProtocol::Upnp, IpFamily::V4) => self.upnp().await?,
+            (RouterProtocol::Natpmp, IpFamily::V4) => self.natpmp().await?,
+            (RouterProtocol::Pcp, family) => self.pcp(family).await?,
+            (protocol, family) => {
+                return Err(AppErr::ip_discovery(format!(
+                    "{:?} cannot look up an {} address",
+                    protocol, family
+                )))
+            }
+        };
+
+        if let IpAddr::V4(wan) = ip {
+            if is_cgnat(&wan) {
+                self.nat_policy.apply(
+                    &format_args!("router({})", self),
+                    format!("WAN address {} is a carrier-grade NAT address", wan),
+                )?;
+            }
+        }
+
+        Ok(ip)
+    }
+}
+
+impl std::fmt::Display for RouterSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let protocol = match self.protocol {
+            RouterProtocol::Upnp => "upnp",
+            RouterProtocol::Natpmp => "natpmp",
+            RouterProtocol::Pcp => "pcp",
+        };
+        match &self.gateway {
+            Some(gateway) => write!(f, "{} @{}", protocol, gateway),
+            None => write!(f, "{}", protocol),
+        }
+    }
+}
+
+// PCP carries all addresses as IPv6, IPv4 ones are mapped.
+fn to_pcp_addr(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+// The text of the first `<tag>` element, namespace prefixes are ignored.
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find('<')?;
+    Some(&xml[start..end])
+}
+
+// Returns the serviceType and controlURL of the WAN connection service.
+fn find_wan_service(description: &str) -> Option<(String, String)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = xml_text(service, "serviceType")?.trim();
+        if !WAN_SERVICES.iter().any(|s| service_type.contains(s)) {
+            return None;
+        }
+        let control_url = xml_text(service, "controlURL")?.trim();
+        Some((service_type.to_owned(), control_url.to_owned()))
+    })
+}
+
+// Each line of /proc/net/route is:
+// Iface Destination Gateway Flags RefCnt Use Metric Mask MTU Window IRTT
+// with addresses in little endian hex.
+#[cfg(target_os = "linux")]
+fn default_gateway_v4() -> Result<Ipv4Addr> {
+    let content = std::fs::read_to_string("/proc/net/route")?;
+
+    content
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || fields[1] != "00000000" {
+                return None;
+            }
+            let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+            Some(Ipv4Addr::from(u32::from_be(gateway)))
+        })
+        .ok_or(AppErr::ip_discovery("no IPv4 default route".to_string()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway_v4() -> Result<Ipv4Addr> {
+    Err(AppErr::ip_discovery(
+        "gateway must be configured on this platform".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root><device><serviceList>
+<service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+<controlURL>/l3f</controlURL></service>
+<service>
+  <serviceType> urn:schemas-upnp-org:service:WANPPPConnection:1 </serviceType>
+  <controlURL> /ctl/PPPConn </controlURL>
+</service>
+</serviceList></device></root>"#;
+
+    fn router(
+        protocol: RouterProtocol,
+        gateway: SocketAddr,
+        nat_policy: NatPolicy,
+    ) -> RouterSource {
+        RouterSource {
+            protocol,
+            gateway: Some(gateway.to_string()),
+            location: None,
+            nat_policy,
+        }
+    }
+
+    // Answers every request with the result of `answer`, until the test ends.
+    async fn udp_stub<F>(answer: F) -> SocketAddr
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        let stub = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = stub.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            loop {
+                let (len, from) = stub.recv_from(&mut buf).await.unwrap();
+                stub.send_to(&answer(&buf[..len]), from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    // An IGD serving its description with `status`, and answering
+    // GetExternalIPAddress with `wan`.
+    async fn igd(status: u16, wan: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Reads the headers and as much of the body as they announce.
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&req);
+                    let Some(end) = text.find("\r\n\r\n") else {
+                        continue;
+                    };
+                    let len = text
+                        .lines()
+                        .find_map(|l| {
+                            l.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().parse().unwrap())
+                        })
+                        .unwrap_or(0usize);
+                    if req.len() >= end + 4 + len {
+                        break;
+                    }
+                }
+
+                let req = String::from_utf8_lossy(&req);
+                let (status, body) = if req.starts_with("GET /rootDesc.xml") {
+                    (status, DESCRIPTION.to_owned())
+                } else if req.starts_with("POST /ctl/PPPConn")
+                    && req.contains("WANPPPConnection:1#GetExternalIPAddress")
+                {
+                    let body = format!(
+                        "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                         <NewExternalIPAddress>{}</NewExternalIPAddress>\
+                         </u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+                        wan
+                    );
+                    (200, body)
+                } else {
+                    (500, "unexpected request".to_owned())
+                };
+
+                let resp = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn xml_text_ignores_namespaces() {
+        let xml = "<u:R><NewExternalIPAddress>192.0.2.1</NewExternalIPAddress></u:R>";
+        assert_eq!(xml_text(xml, "NewExternalIPAddress"), Some("192.0.2.1"));
+        let xml = "<u:NewExternalIPAddress>192.0.2.2</u:NewExternalIPAddress>";
+        assert_eq!(xml_text(xml, "NewExternalIPAddress"), Some("192.0.2.2"));
+        assert_eq!(xml_text(xml, "controlURL"), None);
+    }
+
+    #[test]
+    fn find_wan_service_skips_other_services() {
+        assert_eq!(
+            find_wan_service(DESCRIPTION),
+            Some((
+                "urn:schemas-upnp-org:service:WANPPPConnection:1".to_owned(),
+                "/ctl/PPPConn".to_owned()
+            ))
+        );
+        assert_eq!(
+            find_wan_service(&DESCRIPTION.replace("WANPPPConnection", "WANCommonInterfaceConfig")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn upnp_asks_the_wan_service() {
+        let addr = igd(200, "198.51.100.2").await;
+        let source = RouterSource {
+            protocol: RouterProtocol::Upnp,
+            gateway: None,
+            location: Some(format!("http://{}/rootDesc.xml", addr)),
+            nat_policy: NatPolicy::Refuse,
+        };
+        assert_eq!(
+            source.lookup(IpFamily::V4).await.unwrap(),
+            "198.51.100.2".parse::<IpAddr>().unwrap()
+        );
+
+        let addr = igd(404, "198.51.100.2").await;
+        let source = RouterSource {
+            location: Some(format!("http://{}/rootDesc.xml", addr)),
+            ..source
+        };
+        let err = source.lookup(IpFamily::V4).await.unwrap_err();
+        assert!(err.to_string().contains("404"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn natpmp_reads_the_external_address() {
+        let gateway = udp_stub(|req| {
+            assert_eq!(req, [0, 0]);
+            vec![0, 128, 0, 0, 0, 0, 0, 9, 198, 51, 100, 9]
+        })
+        .await;
+        let ip = router(RouterProtocol::Natpmp, gateway, NatPolicy::Refuse)
+            .lookup(IpFamily::V4)
+            .await
+            .unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 9)));
+
+        // result code 3: network failure
+        let gateway = udp_stub(|_| vec![0, 128, 0, 3, 0, 0, 0, 9, 0, 0, 0, 0]).await;
+        let err = router(RouterProtocol::Natpmp, gateway, NatPolicy::Warn)
+            .lookup(IpFamily::V4)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("result code 3"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn carrier_grade_nat_wan_address_follows_the_policy() {
+        let answer = |_: &[u8]| vec![0, 128, 0, 0, 0, 0, 0, 9, 100, 64, 1, 2];
+
+        let gateway = udp_stub(answer).await;
+        let ip = router(RouterProtocol::Natpmp, gateway, NatPolicy::Warn)
+            .lookup(IpFamily::V4)
+            .await
+            .unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(100, 64, 1, 2)));
+
+        let err = router(RouterProtocol::Natpmp, gateway, NatPolicy::Refuse)
+            .lookup(IpFamily::V4)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("carrier-grade"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn pcp_maps_and_deletes_the_mapping() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let gateway = udp_stub(move |req| {
+            assert_eq!(req.len(), 60);
+            assert_eq!(req[..2], [PCP_VERSION, PCP_OPCODE_MAP]);
+            let lifetime = u32::from_be_bytes(req[4..8].try_into().unwrap());
+            tx.send((lifetime, req[24..36].to_vec())).unwrap();
+
+            let mut resp = vec![PCP_VERSION, PCP_RESPONSE_BIT | PCP_OPCODE_MAP, 0, 0];
+            resp.extend_from_slice(&lifetime.to_be_bytes());
+            resp.extend_from_slice(&[0; 16]);
+            // The nonce, protocol and internal port are echoed.
+            resp.extend_from_slice(&req[24..42]);
+            resp.extend_from_slice(&req[42..44]);
+            resp.extend_from_slice(&Ipv4Addr::new(203, 0, 113, 77).to_ipv6_mapped().octets());
+            resp
+        })
+        .await;
+
+        let ip = router(RouterProtocol::Pcp, gateway, NatPolicy::Refuse)
+            .lookup(IpFamily::V4)
+            .await
+            .unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 77)));
+
+        let (lifetime, nonce) = rx.recv().await.unwrap();
+        assert_eq!(lifetime, PCP_MAP_LIFETIME);
+        let (lifetime, delete_nonce) = rx.recv().await.unwrap();
+        assert_eq!(lifetime, 0);
+        assert_eq!(delete_nonce, nonce);
+    }
+}
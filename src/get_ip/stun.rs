@@ -0,0 +1,337 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::{
+    bind_addr, is_cgnat, local_ip, resolve_server, udp_request, IpFamily, IpLookup, NatPolicy,
+};
+use crate::err::*;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+const STUN_PORT: u16 = 3478;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+// The mapped address reported by a STUN (RFC 5389) server.
+#[derive(Debug, Deserialize)]
+pub(super) struct StunSource {
+    // Host name or address of the server, with an optional port.
+    server: String,
+    // A second server asked from the same socket. If it sees another mapping
+    // we are behind a symmetric NAT.
+    #[serde(default)]
+    check_server: Option<String>,
+    // What to do if this host sends from a carrier-grade NAT (100.64.0.0/10)
+    // address or is behind a symmetric NAT, neither of them can be reached
+    // from the internet.
+    #[serde(default)]
+    nat_policy: NatPolicy,
+}
+
+impl StunSource {
+    fn unreachable(&self, reason: String) -> Result<()> {
+        self.nat_policy
+            .apply(&format_args!("stun({})", self.server), reason)
+    }
+}
+
+#[async_trait]
+impl IpLookup for StunSource {
+    async fn lookup(&self, family: IpFamily) -> Result<IpAddr> {
+        let server = resolve_server(&self.server, STUN_PORT, family).await?;
+        let socket = UdpSocket::bind(bind_addr(&server)).await?;
+
+        let mapped = binding_request(&socket, server).await?;
+
+        // The server only ever sees a public address. Behind a carrier-grade
+        // NAT it is the address we send from that is in the shared space.
+        let local = local_ip(server).await?;
+        if local != mapped.ip() {
+            debug!(
+                "stun({}): {} is translated to {}",
+                self.server,
+                local,
+                mapped.ip()
+            );
+        }
+        if let IpAddr::V4(ip) = local {
+            if is_cgnat(&ip) {
+                self.unreachable(format!(
+                    "{} is a carrier-grade NAT address, {} is shared",
+                    ip,
+                    mapped.ip()
+                ))?;
+            }
+        }
+
+        if let Some(check_server) = &self.check_server {
+            let check_server = resolve_server(check_server, STUN_PORT, family).await?;
+            let check_mapped = binding_request(&socket, check_server).await?;
+            if check_mapped != mapped {
+                self.unreachable(format!(
+                    "symmetric NAT detected, mapped to {} by {} but {} by {}",
+                    mapped, server, check_mapped, check_server
+                ))?;
+            }
+        }
+
+        Ok(mapped.ip())
+    }
+}
+
+impl std::fmt::Display for StunSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.server)
+    }
+}
+
+async fn binding_request(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr> {
+    let mut txid = [0u8; 12];
+    txid.iter_mut().for_each(|b| *b = fastrand::u8(..));
+
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&txid);
+
+    let resp = udp_request(socket, server, &request, |resp| {
+        resp.len() >= 20 && resp[8..20] == txid
+    })
+    .await?;
+
+    parse_binding_response(&resp, &txid)
+}
+
+fn malformed() -> AppErr {
+    AppErr::ip_discovery("malformed stun response".to_string())
+}
+
+fn parse_binding_response(buf: &[u8], txid: &[u8; 12]) -> Result<SocketAddr> {
+    let msg_type = u16::from_be_bytes([buf[0], buf[1]]);
+    if msg_type != BINDING_RESPONSE {
+        return Err(AppErr::ip_discovery(format!(
+            "unexpected stun message type {:#06x}",
+            msg_type
+        )));
+    }
+
+    let msg_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    let attrs = buf.get(20..20 + msg_len).ok_or_else(malformed)?;
+
+    let mut mapped = None;
+    let mut pos = 0;
+    while pos + 4 <= attrs.len() {
+        let attr_type = u16::from_be_bytes([attrs[pos], attrs[pos + 1]]);
+        let attr_len = u16::from_be_bytes([attrs[pos + 2], attrs[pos + 3]]) as usize;
+        let value = attrs
+            .get(pos + 4..pos + 4 + attr_len)
+            .ok_or_else(malformed)?;
+        // attributes are padded to a multiple of 4 bytes
+        pos += 4 + attr_len.div_ceil(4) * 4;
+
+        match attr_type {
+            ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(txid)),
+            ATTR_MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {}
+        }
+    }
+
+    mapped.ok_or(AppErr::ip_discovery(
+        "no mapped address in stun response".to_string(),
+    ))
+}
+
+// Parses a (XOR-)MAPPED-ADDRESS value, `txid` is given for the XOR variant.
+fn parse_address(value: &[u8], txid: Option<&[u8; 12]>) -> Result<SocketAddr> {
+    let header = value.get(..4).ok_or_else(malformed)?;
+    let mut port = u16::from_be_bytes([header[2], header[3]]);
+
+    let mut mask = [0u8; 16];
+    if let Some(txid) = txid {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(txid);
+    }
+
+    let ip = match header[1] {
+        0x01 => {
+            let mut octets: [u8; 4] = value.get(4..8).ok_or_else(malformed)?.try_into().unwrap();
+            octets.iter_mut().zip(mask).for_each(|(b, m)| *b ^= m);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 => {
+            let mut octets: [u8; 16] = value.get(4..20).ok_or_else(malformed)?.try_into().unwrap();
+            octets.iter_mut().zip(mask).for_each(|(b, m)| *b ^= m);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        family => {
+            return Err(AppErr::ip_discovery(format!(
+                "unknown stun address family {}",
+                family
+            )))
+        }
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The transaction id of the sample responses of RFC 5769.
+    const TXID: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    fn response(txid: &[u8; 12], attrs: &[u8]) -> Vec<u8> {
+        let mut buf = BINDING_RESPONSE.to_be_bytes().to_vec();
+        buf.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(txid);
+        buf.extend_from_slice(attrs);
+        buf
+    }
+
+    // SOFTWARE "abc", padded to 4 bytes.
+    const SOFTWARE: [u8; 8] = [0x80, 0x22, 0x00, 0x03, b'a', b'b', b'c', 0x00];
+
+    #[test]
+    fn xor_mapped_address_v4() {
+        // RFC 5769 2.2, 192.0.2.1 port 32853
+        let mut attrs = SOFTWARE.to_vec();
+        attrs.extend([
+            0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+        ]);
+
+        let mapped = parse_binding_response(&response(&TXID, &attrs), &TXID).unwrap();
+        assert_eq!(mapped, "192.0.2.1:32853".parse().unwrap());
+    }
+
+    #[test]
+    fn xor_mapped_address_v6() {
+        // RFC 5769 2.3, 2001:db8:1234:5678:11:2233:4455:6677 port 32853
+        let attrs = [
+            0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3,
+            0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        ];
+
+        let mapped = parse_binding_response(&response(&TXID, &attrs), &TXID).unwrap();
+        assert_eq!(
+            mapped,
+            "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+                .parse()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn mapped_address_is_the_fallback() {
+        // 203.0.113.7 port 4660, not XORed.
+        let mapped_attr = [
+            0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x12, 0x34, 203, 0, 113, 7,
+        ];
+        let buf = response(&TXID, &mapped_attr);
+        let mapped = parse_binding_response(&buf, &TXID).unwrap();
+        assert_eq!(mapped, "203.0.113.7:4660".parse().unwrap());
+
+        // XOR-MAPPED-ADDRESS wins, wherever it is.
+        let mut attrs = mapped_attr.to_vec();
+        attrs.extend([
+            0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+        ]);
+        let mapped = parse_binding_response(&response(&TXID, &attrs), &TXID).unwrap();
+        assert_eq!(mapped, "192.0.2.1:32853".parse().unwrap());
+    }
+
+    #[test]
+    fn malformed_responses_fail() {
+        // No address at all.
+        parse_binding_response(&response(&TXID, &SOFTWARE), &TXID).unwrap_err();
+        // An attribute longer than the message.
+        let attrs = [0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47];
+        parse_binding_response(&response(&TXID, &attrs), &TXID).unwrap_err();
+        // A length beyond the datagram.
+        let mut buf = response(&TXID, &[]);
+        buf[3] = 8;
+        parse_binding_response(&buf, &TXID).unwrap_err();
+        // An error response.
+        let mut buf = response(&TXID, &[]);
+        buf[..2].copy_from_slice(&0x0111u16.to_be_bytes());
+        parse_binding_response(&buf, &TXID).unwrap_err();
+        // An unknown address family.
+        parse_address(&[0x00, 0x03, 0x00, 0x00, 1, 2, 3, 4], None).unwrap_err();
+    }
+
+    // A STUN server on IPv4 loopback, answering a binding request with the
+    // address it came from, but with the port increased by `port_offset` as
+    // a NAT mapping differently per destination would.
+    async fn stun_server(port_offset: u16) -> SocketAddr {
+        let stub = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = stub.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            loop {
+                let (len, from) = stub.recv_from(&mut buf).await.unwrap();
+                assert_eq!(len, 20);
+                assert_eq!(buf[..2], BINDING_REQUEST.to_be_bytes());
+                let txid: [u8; 12] = buf[8..20].try_into().unwrap();
+
+                let IpAddr::V4(ip) = from.ip() else {
+                    unreachable!()
+                };
+                let port = (from.port() + port_offset) ^ (MAGIC_COOKIE >> 16) as u16;
+                let ip = u32::from(ip) ^ MAGIC_COOKIE;
+                let mut attrs = vec![0x00, 0x20, 0x00, 0x08, 0x00, 0x01];
+                attrs.extend(port.to_be_bytes());
+                attrs.extend(ip.to_be_bytes());
+
+                stub.send_to(&response(&txid, &attrs), from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn source(yaml: &str) -> StunSource {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn lookup_asks_the_server() {
+        let server = stun_server(0).await;
+        let check = stun_server(0).await;
+
+        let stun = source(&format!(
+            "{{server: '{}', check_server: '{}', nat_policy: refuse}}",
+            server, check
+        ));
+        let ip = stun.lookup(IpFamily::V4).await.unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    #[tokio::test]
+    async fn symmetric_nat_is_detected() {
+        let server = stun_server(0).await;
+        let check = stun_server(1).await;
+
+        let stun = source(&format!(
+            "{{server: '{}', check_server: '{}', nat_policy: refuse}}",
+            server, check
+        ));
+        let err = stun.lookup(IpFamily::V4).await.unwrap_err();
+        assert!(err.to_string().contains("symmetric NAT"), "{}", err);
+
+        let stun = source(&format!(
+            "{{server: '{}', check_server: '{}'}}",
+            server, check
+        ));
+        let ip = stun.lookup(IpFamily::V4).await.unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+}
@@ -1,24 +1,41 @@
 mod backends;
 mod err;
 mod get_ip;
+mod netlink;
 mod options;
+mod schedule;
+mod state;
 mod yaml_parse;
 
-use crate::backends::{Backend, DNSSync};
+use crate::backends::{Backend, Change, DNSSync, SyncReport, Zones};
 use crate::err::*;
-use crate::options::Options;
+use crate::netlink::Watcher;
+use crate::options::{Options, OutputFormat};
+use crate::schedule::{Schedule, Unit};
 use crate::yaml_parse::*;
 
 use clap::Parser;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+use tokio::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::util::SubscriberInitExt;
 
+// The exit code of a dry run which found changes to apply. 2 is taken by
+// clap for usage errors.
+const EXIT_CHANGES_PENDING: i32 = 3;
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     let opts = Options::parse();
     let mut log_level = tracing::Level::INFO;
-    let mut tracing_builder = tracing_subscriber::fmt();
+    // Keep stdout clean for the JSON plan.
+    let writer = match opts.output {
+        OutputFormat::Text => BoxMakeWriter::new(std::io::stdout),
+        OutputFormat::Json => BoxMakeWriter::new(std::io::stderr),
+    };
+    let mut tracing_builder = tracing_subscriber::fmt().with_writer(writer);
 
     tracing_builder = if let Some(level) = opts.log_level {
         log_level = level;
@@ -35,51 +52,161 @@ async fn main() -> Result<()> {
 
     info!("Thanks for using DNS Syner...");
 
-    let (config, backends) = app_init(opts.config_file).await?;
+    let code = match run(&opts).await {
+        Ok(code) => code,
+        Err(e @ AppErr::Shutdown { .. }) => {
+            info!("{}", e);
+            e.exit_code()
+        }
+        Err(e) => {
+            error!("{}", e);
+            e.exit_code()
+        }
+    };
+
+    std::process::exit(code);
+}
+
+// Returns the exit code.
+async fn run(opts: &Options) -> Result<i32> {
+    let (config, backends) = app_init(&opts.config_file).await?;
     info!("The Configuration is: {:?}", config);
 
     for (i, backend) in backends.iter().enumerate() {
         debug!("Backend[{}]: {:?}", i, backend);
     }
 
-    if config.check_interval == 0 {
-        run_once(&config, &backends).await?;
+    if opts.is_dry_run() {
+        let changes = run_plan(&config, &backends).await?;
+        if opts.output == OutputFormat::Json {
+            let doc = serde_json::json!({ "changes": changes });
+            println!("{}", serde_json::to_string_pretty(&doc)?);
+        }
+        if !changes.is_empty() {
+            info!("{} changes pending", changes.len());
+            return Ok(EXIT_CHANGES_PENDING);
+        }
+        info!("No changes pending");
+    } else if config.check_interval == 0 {
+        let all = Schedule::new(&backends, 0).all();
+        run_once(&config, &backends, &all).await.into_result()?;
     } else {
         warn!("Running in blocking mode");
         run_as_blocking(&config, &backends).await?;
     }
 
-    Ok(())
+    Ok(0)
 }
 
 async fn run_as_blocking(config: &Config, backends: &[Backend]) -> Result<()> {
-    let mut interval =
-        tokio::time::interval(tokio::time::Duration::from_secs(config.check_interval));
+    let mut schedule = Schedule::new(backends, config.check_interval);
+
+    let mut watcher = match &config.netlink_watch {
+        Some(_) => Some(Watcher::new().map_err(|e| e.context("start netlink watch"))?),
+        None => None,
+    };
+    let debounce = config
+        .netlink_watch
+        .as_ref()
+        .map(|watch| watch.debounce())
+        .unwrap_or_default();
 
     let mut sigterm = signal(SignalKind::terminate())?;
     let mut sigint = signal(SignalKind::interrupt())?;
 
+    let mut network_changed = false;
     loop {
-        let sync_result = run_once(config, backends).await;
-        match sync_result {
-            Ok(_) => {}
-            Err(e) => error!("[Sync Failed] {:?}", e),
+        // After a network change every zone is synced, the state cache skips
+        // the ones which do not follow a changed address.
+        let due = if network_changed {
+            schedule.take_due(Instant::now());
+            schedule.all()
+        } else {
+            schedule.take_due(Instant::now())
+        };
+
+        if !due.is_empty() {
+            run_once(config, backends, &due).await;
         }
 
-        tokio::select! {
-            _ = sigterm.recv() => { return Err(AppErr{ msg: "Receive SIGTERM, quit".to_string() } )  },
-            _ = sigint.recv() => { return Err(AppErr{ msg: "Receive SIGINT, quit".to_string() } ) },
-            _ = interval.tick() => {},
+        let changed = tokio::select! {
+            _ = sigterm.recv() => { return Err(AppErr::shutdown("SIGTERM")) },
+            _ = sigint.recv() => { return Err(AppErr::shutdown("SIGINT")) },
+            _ = tokio::time::sleep_until(schedule.next_due()) => None,
+            changed = wait_network_change(&mut watcher, debounce) => Some(changed),
+        };
+
+        network_changed = match changed {
+            Some(Ok(())) => {
+                info!("network changed, sync now");
+                true
+            }
+            // The check interval keeps the zones in sync without the watcher.
+            Some(Err(e)) => {
+                error!("netlink watch failed, stop watching: {}", e);
+                watcher = None;
+                false
+            }
+            None => false,
         };
     }
 }
 
-async fn run_once(_config: &Config, backends: &[Backend]) -> Result<()> {
-    let v4addr = get_ip::get_pub_ip_v4().await?;
+// Never completes without a watcher.
+async fn wait_network_change(watcher: &mut Option<Watcher>, debounce: Duration) -> Result<()> {
+    match watcher {
+        Some(watcher) => watcher.changed(debounce).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn run_plan(config: &Config, backends: &[Backend]) -> Result<Vec<Change>> {
+    let wanted: Vec<_> = backends
+        .iter()
+        .flat_map(|b| b.wanted_ips(Zones::All))
+        .collect();
+    let addrs = config.ip_sources.resolve(&wanted).await;
 
+    let mut changes = Vec::new();
     for backend in backends.iter() {
-        backend.sync(&v4addr).await?;
+        changes.extend(backend.plan(&addrs).await?);
     }
 
-    Ok(())
+    Ok(changes)
+}
+
+// Syncs the given zones. Backends and their zones are synced concurrently, at
+// most `concurrency` zones at a time.
+async fn run_once(config: &Config, backends: &[Backend], units: &[Unit]) -> SyncReport {
+    let selected: Vec<Vec<usize>> = (0..backends.len())
+        .map(|i| {
+            units
+                .iter()
+                .filter(|unit| unit.backend == i)
+                .map(|unit| unit.zone)
+                .collect()
+        })
+        .collect();
+
+    let wanted: Vec<_> = backends
+        .iter()
+        .zip(selected.iter())
+        .flat_map(|(backend, zones)| backend.wanted_ips(Zones::Only(zones)))
+        .collect();
+    let addrs = config.ip_sources.resolve(&wanted).await;
+
+    let limit = Semaphore::new(config.concurrency);
+    let syncs = backends
+        .iter()
+        .zip(selected.iter())
+        .map(|(backend, zones)| backend.sync(Zones::Only(zones), &addrs, &config.state, &limit));
+    let units = futures::future::join_all(syncs)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let report = SyncReport { units };
+    report.log();
+    report
 }
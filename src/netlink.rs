@@ -0,0 +1,584 @@
+use crate::err::*;
+
+use serde::Deserialize;
+
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::os::fd::OwnedFd;
+
+fn default_debounce_ms() -> u64 {
+    2000
+}
+
+// Syncs right after an address or the default route of this host changed,
+// e.g. after a PPPoE reconnect, instead of at the next check interval.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NetlinkWatch {
+    // Changes come in bursts, e.g. an address and then the default route. The
+    // sync starts once no further change was seen for this long.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+}
+
+impl NetlinkWatch {
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
+    }
+}
+
+// Receives the address and route events of rtnetlink. Only Linux is
+// supported.
+#[cfg(target_os = "linux")]
+pub struct Watcher {
+    fd: tokio::io::unix::AsyncFd<OwnedFd>,
+    buf: Vec<u8>,
+    // What the events are compared against, a change is only reported if it
+    // differs from what is known already.
+    state: NetState,
+    // When the sync is due for a change seen so far. It is kept here, so a
+    // wait cancelled by the caller, e.g. for a scheduled sync, resumes the
+    // debounce instead of dropping the change.
+    pending: Option<tokio::time::Instant>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    pub fn new() -> Result<Self> {
+        // Subscribed before the dump, so no change falls in between. Events
+        // already part of the dump change nothing when received later.
+        let fd = open(
+            libc::SOCK_NONBLOCK,
+            (libc::RTMGRP_IPV4_IFADDR
+                | libc::RTMGRP_IPV6_IFADDR
+                | libc::RTMGRP_IPV4_ROUTE
+                | libc::RTMGRP_IPV6_ROUTE) as u32,
+        )?;
+
+        Ok(Self {
+            fd: tokio::io::unix::AsyncFd::new(fd)?,
+            buf: vec![0; 64 * 1024],
+            state: NetState::dump()?,
+            pending: None,
+        })
+    }
+
+    // Waits for a change, and then until no further change is seen for
+    // `debounce`. Cancel safe: a change seen by a cancelled call is reported
+    // by the next one.
+    pub async fn changed(&mut self, debounce: Duration) -> Result<()> {
+        loop {
+            let changed = match self.pending {
+                None => self.recv().await?,
+                Some(deadline) => match tokio::time::timeout_at(deadline, self.recv()).await {
+                    Err(_) => {
+                        self.pending = None;
+                        return Ok(());
+                    }
+                    Ok(changed) => changed?,
+                },
+            };
+            if changed {
+                self.pending = Some(tokio::time::Instant::now() + debounce);
+            }
+        }
+    }
+
+    // Reads one datagram, returns whether it holds a change worth a sync. No
+    // datagram is lost if cancelled, it is only read once readable.
+    async fn recv(&mut self) -> Result<bool> {
+        use std::os::fd::AsRawFd;
+
+        let Self { fd, buf, state, .. } = self;
+        loop {
+            let mut guard = fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                // SAFETY: `buf` is valid for writes of its length.
+                let n = unsafe {
+                    libc::recv(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if n < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+
+            match result {
+                Ok(Ok(n)) => return Ok(state.apply(&buf[..n])),
+                // The kernel dropped events, some of them may be relevant.
+                // What is known is read again, as the lost events did not
+                // update it.
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    *state = NetState::dump()?;
+                    return Ok(true);
+                }
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct Watcher;
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    pub fn new() -> Result<Self> {
+        Err(AppErr::config(
+            "netlink_watch is only supported on Linux".to_string(),
+        ))
+    }
+
+    pub async fn changed(&mut self, _debounce: Duration) -> Result<()> {
+        std::future::pending().await
+    }
+}
+
+// A NETLINK_ROUTE socket, bound to the multicast `groups`.
+#[cfg(target_os = "linux")]
+fn open(flags: libc::c_int, groups: u32) -> Result<OwnedFd> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    // SAFETY: plain syscall, the returned fd is owned below.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | flags,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // SAFETY: `fd` is a new socket not owned by anything else.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // SAFETY: sockaddr_nl is plain old data, all zero is a valid value.
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = groups;
+
+    // SAFETY: `addr` is a valid sockaddr_nl of the given size.
+    let rc = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(fd)
+}
+
+// See include/uapi/linux/netlink.h and rtnetlink.h. Messages are aligned to
+// 4 bytes, the payload follows the 16 bytes header.
+#[cfg(target_os = "linux")]
+const NLMSG_HDRLEN: usize = 16;
+
+// The fixed part of the payload, struct ifaddrmsg and struct rtmsg. Their
+// attributes follow.
+#[cfg(target_os = "linux")]
+const IFADDRMSG_LEN: usize = 8;
+#[cfg(target_os = "linux")]
+const RTMSG_LEN: usize = 12;
+
+// From include/uapi/linux/if_addr.h
+#[cfg(target_os = "linux")]
+const IFA_F_DEPRECATED: u32 = 0x20;
+#[cfg(target_os = "linux")]
+const IFA_FLAGS: u16 = 8;
+
+// The type and payload of each message in `buf`.
+#[cfg(target_os = "linux")]
+fn messages(buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        if pos + NLMSG_HDRLEN > buf.len() {
+            return None;
+        }
+        let len = u32::from_ne_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]) as usize;
+        let msg_type = u16::from_ne_bytes([buf[pos + 4], buf[pos + 5]]);
+        if len < NLMSG_HDRLEN || pos + len > buf.len() {
+            return None;
+        }
+        let payload = &buf[pos + NLMSG_HDRLEN..pos + len];
+        pos += (len + 3) & !3;
+        Some((msg_type, payload))
+    })
+}
+
+// The type and value of each struct rtattr in `buf`, aligned like messages.
+#[cfg(target_os = "linux")]
+fn attributes(buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        if pos + 4 > buf.len() {
+            return None;
+        }
+        let len = u16::from_ne_bytes([buf[pos], buf[pos + 1]]) as usize;
+        let attr_type = u16::from_ne_bytes([buf[pos + 2], buf[pos + 3]]);
+        if len < 4 || pos + len > buf.len() {
+            return None;
+        }
+        let value = &buf[pos + 4..pos + len];
+        pos += (len + 3) & !3;
+        Some((attr_type, value))
+    })
+}
+
+// What an address or default route is told apart by. Lifetimes are left
+// out: Router Advertisements refresh them every few minutes without
+// changing the public address.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Entry {
+    Addr {
+        index: u32,
+        // IFA_LOCAL and IFA_ADDRESS differ on point to point links, where
+        // the latter is the peer.
+        local: Vec<u8>,
+        address: Vec<u8>,
+    },
+    Route {
+        family: u8,
+        oif: Vec<u8>,
+        gateway: Vec<u8>,
+        priority: Vec<u8>,
+    },
+}
+
+#[cfg(target_os = "linux")]
+impl Entry {
+    // Addresses with global scope, and default routes in the main table. The
+    // others, e.g. link-local addresses and routes to the LAN, do not change
+    // the public address. Returned with whether an address is deprecated,
+    // which the interface ip source takes into account.
+    fn parse(msg_type: u16, payload: &[u8]) -> Option<(Self, bool)> {
+        match msg_type {
+            // struct ifaddrmsg: family, prefixlen, flags, scope, index
+            libc::RTM_NEWADDR | libc::RTM_DELADDR => {
+                let header = payload.get(..IFADDRMSG_LEN)?;
+                if header[3] != libc::RT_SCOPE_UNIVERSE {
+                    return None;
+                }
+                let index = u32::from_ne_bytes([header[4], header[5], header[6], header[7]]);
+                let mut flags = u32::from(header[2]);
+                let (mut local, mut address) = (Vec::new(), Vec::new());
+                for (attr_type, value) in attributes(&payload[IFADDRMSG_LEN..]) {
+                    match attr_type {
+                        libc::IFA_LOCAL => local = value.to_vec(),
+                        libc::IFA_ADDRESS => address = value.to_vec(),
+                        IFA_FLAGS if value.len() == 4 => {
+                            flags = u32::from_ne_bytes([value[0], value[1], value[2], value[3]])
+                        }
+                        _ => {}
+                    }
+                }
+                Some((
+                    Entry::Addr {
+                        index,
+                        local,
+                        address,
+                    },
+                    flags & IFA_F_DEPRECATED != 0,
+                ))
+            }
+            // struct rtmsg: family, dst_len, src_len, tos, table, ...
+            libc::RTM_NEWROUTE | libc::RTM_DELROUTE => {
+                let header = payload.get(..RTMSG_LEN)?;
+                if header[1] != 0 {
+                    return None;
+                }
+                let mut table = u32::from(header[4]);
+                let (mut oif, mut gateway, mut priority) = (Vec::new(), Vec::new(), Vec::new());
+                for (attr_type, value) in attributes(&payload[RTMSG_LEN..]) {
+                    match attr_type {
+                        libc::RTA_TABLE if value.len() == 4 => {
+                            table = u32::from_ne_bytes([value[0], value[1], value[2], value[3]])
+                        }
+                        libc::RTA_OIF => oif = value.to_vec(),
+                        libc::RTA_GATEWAY => gateway = value.to_vec(),
+                        libc::RTA_PRIORITY => priority = value.to_vec(),
+                        _ => {}
+                    }
+                }
+                if table != u32::from(libc::RT_TABLE_MAIN) {
+                    return None;
+                }
+                Some((
+                    Entry::Route {
+                        family: header[0],
+                        oif,
+                        gateway,
+                        priority,
+                    },
+                    false,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+// The global addresses and default routes of this host.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+struct NetState {
+    // Whether each one is deprecated.
+    entries: HashMap<Entry, bool>,
+}
+
+#[cfg(target_os = "linux")]
+impl NetState {
+    // Reads the addresses and routes the host has now.
+    fn dump() -> Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let fd = open(0, 0)?;
+        let mut state = Self::default();
+        let mut buf = vec![0u8; 64 * 1024];
+
+        for (seq, (msg_type, len)) in [
+            (libc::RTM_GETADDR, IFADDRMSG_LEN),
+            (libc::RTM_GETROUTE, RTMSG_LEN),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            // struct nlmsghdr: len, type, flags, seq, pid, and a zeroed
+            // ifaddrmsg or rtmsg of family AF_UNSPEC, which asks for all.
+            let mut req = Vec::with_capacity(NLMSG_HDRLEN + len);
+            req.extend_from_slice(&((NLMSG_HDRLEN + len) as u32).to_ne_bytes());
+            req.extend_from_slice(&msg_type.to_ne_bytes());
+            req.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+            req.extend_from_slice(&(seq as u32 + 1).to_ne_bytes());
+            req.extend_from_slice(&0u32.to_ne_bytes());
+            req.resize(NLMSG_HDRLEN + len, 0);
+
+            // SAFETY: `req` is valid for reads of its length.
+            let n = unsafe {
+                libc::send(
+                    fd.as_raw_fd(),
+                    req.as_ptr() as *const libc::c_void,
+                    req.len(),
+                    0,
+                )
+            };
+            if n < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            'dump: loop {
+                // SAFETY: `buf` is valid for writes of its length.
+                let n = unsafe {
+                    libc::recv(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if n < 0 {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+                for (msg_type, payload) in messages(&buf[..n as usize]) {
+                    match i32::from(msg_type) {
+                        libc::NLMSG_DONE => break 'dump,
+                        libc::NLMSG_ERROR => {
+                            // struct nlmsgerr: a negative errno, then the request.
+                            let errno = payload
+                                .get(..4)
+                                .map(|e| i32::from_ne_bytes([e[0], e[1], e[2], e[3]]))
+                                .unwrap_or(0);
+                            return Err(std::io::Error::from_raw_os_error(-errno).into());
+                        }
+                        _ => {
+                            state.apply_one(msg_type, payload);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(state)
+    }
+
+    // Applies the events in `buf`, returns whether any of them changed a
+    // global address or a default route.
+    fn apply(&mut self, buf: &[u8]) -> bool {
+        messages(buf).fold(false, |changed, (msg_type, payload)| {
+            self.apply_one(msg_type, payload) || changed
+        })
+    }
+
+    fn apply_one(&mut self, msg_type: u16, payload: &[u8]) -> bool {
+        let Some((entry, deprecated)) = Entry::parse(msg_type, payload) else {
+            return false;
+        };
+        match msg_type {
+            libc::RTM_NEWADDR | libc::RTM_NEWROUTE => {
+                self.entries.insert(entry, deprecated) != Some(deprecated)
+            }
+            _ => self.entries.remove(&entry).is_some(),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn attr(attr_type: u16, value: &[u8]) -> Vec<u8> {
+        let mut buf = ((4 + value.len()) as u16).to_ne_bytes().to_vec();
+        buf.extend_from_slice(&attr_type.to_ne_bytes());
+        buf.extend_from_slice(value);
+        buf.resize((buf.len() + 3) & !3, 0);
+        buf
+    }
+
+    fn message(msg_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = ((NLMSG_HDRLEN + payload.len()) as u32)
+            .to_ne_bytes()
+            .to_vec();
+        buf.extend_from_slice(&msg_type.to_ne_bytes());
+        buf.resize(NLMSG_HDRLEN, 0);
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    // An IPv6 address on interface 2, with the lifetimes Router
+    // Advertisements refresh.
+    fn addr(msg_type: u16, scope: u8, ip: [u8; 16], flags: u32, valid_lft: u32) -> Vec<u8> {
+        let mut payload = vec![libc::AF_INET6 as u8, 64, 0, scope];
+        payload.extend_from_slice(&2u32.to_ne_bytes());
+        payload.extend(attr(libc::IFA_ADDRESS, &ip));
+        payload.extend(attr(IFA_FLAGS, &flags.to_ne_bytes()));
+        // struct ifa_cacheinfo: preferred, valid, created, updated
+        let cacheinfo: Vec<u8> = [valid_lft / 2, valid_lft, 100, valid_lft]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+        payload.extend(attr(libc::IFA_CACHEINFO, &cacheinfo));
+        message(msg_type, &payload)
+    }
+
+    fn route(msg_type: u16, dst_len: u8, table: u8, gateway: [u8; 16], expires: u32) -> Vec<u8> {
+        let mut payload = vec![libc::AF_INET6 as u8, dst_len, 0, 0, table, 9, 0, 1];
+        payload.extend_from_slice(&0u32.to_ne_bytes());
+        payload.extend(attr(libc::RTA_TABLE, &u32::from(table).to_ne_bytes()));
+        payload.extend(attr(libc::RTA_OIF, &2u32.to_ne_bytes()));
+        payload.extend(attr(libc::RTA_GATEWAY, &gateway));
+        payload.extend(attr(libc::RTA_PRIORITY, &1024u32.to_ne_bytes()));
+        payload.extend(attr(libc::RTA_EXPIRES, &expires.to_ne_bytes()));
+        message(msg_type, &payload)
+    }
+
+    const GLOBAL: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const LINK_LOCAL: [u8; 16] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+    #[test]
+    fn lifetime_refreshes_are_not_changes() {
+        let mut state = NetState::default();
+
+        assert!(state.apply(&addr(libc::RTM_NEWADDR, 0, GLOBAL, 0, 7200)));
+        assert!(!state.apply(&addr(libc::RTM_NEWADDR, 0, GLOBAL, 0, 7100)));
+        assert!(!state.apply(&addr(libc::RTM_NEWADDR, 0, GLOBAL, 0, 7200)));
+
+        assert!(state.apply(&route(
+            libc::RTM_NEWROUTE,
+            0,
+            libc::RT_TABLE_MAIN,
+            LINK_LOCAL,
+            1800
+        )));
+        assert!(!state.apply(&route(
+            libc::RTM_NEWROUTE,
+            0,
+            libc::RT_TABLE_MAIN,
+            LINK_LOCAL,
+            1700
+        )));
+    }
+
+    #[test]
+    fn changes_of_global_addresses_and_default_routes() {
+        let mut state = NetState::default();
+        state.apply(&addr(libc::RTM_NEWADDR, 0, GLOBAL, 0, 7200));
+        state.apply(&route(
+            libc::RTM_NEWROUTE,
+            0,
+            libc::RT_TABLE_MAIN,
+            LINK_LOCAL,
+            1800,
+        ));
+
+        // Deprecated once its preferred lifetime ran out.
+        assert!(state.apply(&addr(libc::RTM_NEWADDR, 0, GLOBAL, IFA_F_DEPRECATED, 60)));
+        assert!(state.apply(&addr(libc::RTM_DELADDR, 0, GLOBAL, IFA_F_DEPRECATED, 0)));
+        assert!(!state.apply(&addr(libc::RTM_DELADDR, 0, GLOBAL, 0, 0)));
+
+        let mut other = LINK_LOCAL;
+        other[15] = 2;
+        assert!(state.apply(&route(
+            libc::RTM_NEWROUTE,
+            0,
+            libc::RT_TABLE_MAIN,
+            other,
+            1800
+        )));
+        assert!(state.apply(&route(
+            libc::RTM_DELROUTE,
+            0,
+            libc::RT_TABLE_MAIN,
+            LINK_LOCAL,
+            0
+        )));
+    }
+
+    #[test]
+    fn other_addresses_and_routes_are_ignored() {
+        let mut state = NetState::default();
+
+        assert!(!state.apply(&addr(
+            libc::RTM_NEWADDR,
+            libc::RT_SCOPE_LINK,
+            LINK_LOCAL,
+            0,
+            0
+        )));
+        // A route to the LAN, and a default route of another table.
+        assert!(!state.apply(&route(
+            libc::RTM_NEWROUTE,
+            64,
+            libc::RT_TABLE_MAIN,
+            GLOBAL,
+            0
+        )));
+        assert!(!state.apply(&route(libc::RTM_NEWROUTE, 0, 100, LINK_LOCAL, 0)));
+        assert!(state.entries.is_empty());
+    }
+
+    #[test]
+    fn one_change_in_a_batch_is_reported() {
+        let mut state = NetState::default();
+        state.apply(&addr(libc::RTM_NEWADDR, 0, GLOBAL, 0, 7200));
+
+        let mut other = GLOBAL;
+        other[15] = 2;
+        let mut buf = addr(libc::RTM_NEWADDR, 0, GLOBAL, 0, 7100);
+        buf.extend(addr(libc::RTM_NEWADDR, 0, other, 0, 7200));
+        assert!(state.apply(&buf));
+        assert_eq!(state.entries.len(), 2);
+    }
+}
@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -7,4 +7,32 @@ pub struct Options {
     pub config_file: String,
     #[arg(long)]
     pub log_level: Option<tracing::Level>,
+    /// Print the changes of one sync without applying them, then exit with 3
+    /// if there are any, or 0 otherwise
+    #[arg(long)]
+    pub dry_run: bool,
+    /// The format of a dry run; json prints the planned changes to stdout
+    /// and the log to stderr
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    pub output: OutputFormat,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Same as --dry-run
+    Plan,
+}
+
+impl Options {
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run || matches!(self.command, Some(Command::Plan))
+    }
 }
@@ -0,0 +1,81 @@
+use crate::backends::{Backend, DNSSync};
+
+use tokio::time::{Duration, Instant};
+
+// A zone of a backend, by their positions in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unit {
+    pub backend: usize,
+    pub zone: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    unit: Unit,
+    interval: Duration,
+    next_due: Instant,
+}
+
+// Tracks when each zone is due for its next sync, according to the check
+// interval of the zone, of its backend, or the global one.
+#[derive(Debug)]
+pub struct Schedule {
+    entries: Vec<Entry>,
+    default_interval: Duration,
+}
+
+impl Schedule {
+    // Every zone is due right away.
+    pub fn new(backends: &[Backend], default_interval: u64) -> Self {
+        let now = Instant::now();
+        let entries = backends
+            .iter()
+            .enumerate()
+            .flat_map(|(backend, b)| {
+                b.check_intervals()
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(zone, interval)| Entry {
+                        unit: Unit { backend, zone },
+                        interval: Duration::from_secs(interval.unwrap_or(default_interval)),
+                        next_due: now,
+                    })
+            })
+            .collect();
+
+        Self {
+            entries,
+            default_interval: Duration::from_secs(default_interval),
+        }
+    }
+
+    pub fn all(&self) -> Vec<Unit> {
+        self.entries.iter().map(|entry| entry.unit).collect()
+    }
+
+    // Returns the zones which are due, and schedules their next sync. A zone
+    // which fell behind, e.g. after a long sync, skips the missed syncs.
+    pub fn take_due(&mut self, now: Instant) -> Vec<Unit> {
+        let mut due = Vec::new();
+        for entry in self.entries.iter_mut() {
+            if entry.next_due > now {
+                continue;
+            }
+
+            due.push(entry.unit);
+            entry.next_due += entry.interval;
+            if entry.next_due <= now {
+                entry.next_due = now + entry.interval;
+            }
+        }
+        due
+    }
+
+    pub fn next_due(&self) -> Instant {
+        self.entries
+            .iter()
+            .map(|entry| entry.next_due)
+            .min()
+            .unwrap_or_else(|| Instant::now() + self.default_interval)
+    }
+}
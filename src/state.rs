@@ -0,0 +1,181 @@
+use crate::err::*;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// What was applied by the last successful sync of a zone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZoneState {
+    // The followed addresses, keyed by ip source and family.
+    pub addrs: BTreeMap<String, String>,
+    pub config_hash: String,
+    // Unix time in seconds at which the sync started, so that a zone
+    // scheduled every `force_refresh` seconds is refreshed at every tick.
+    #[serde(default)]
+    pub synced_at: u64,
+}
+
+impl ZoneState {
+    // `config` is a canonical serialization of the managed config, e.g. JSON
+    // with sorted keys, so the hash stays the same across versions.
+    pub fn new(addrs: BTreeMap<String, String>, config: &str) -> Self {
+        Self {
+            addrs,
+            config_hash: fingerprint(config),
+            synced_at: now(),
+        }
+    }
+}
+
+// Remembers the last successful sync of each zone, so that the provider is
+// only asked for the remote records if something changed locally, the last
+// sync failed, or `force_refresh` seconds have passed.
+#[derive(Debug)]
+pub struct StateCache {
+    // Without a file the state only lives as long as the process.
+    path: Option<PathBuf>,
+    force_refresh: u64,
+    zones: Mutex<HashMap<String, ZoneState>>,
+}
+
+impl StateCache {
+    pub fn load(path: Option<PathBuf>, force_refresh: u64) -> Result<Self> {
+        let zones = match &path {
+            Some(path) if path.exists() => {
+                let content = std::fs::read_to_string(path).map_err(|e| {
+                    AppErr::from(e).context(&format!("read state file {}", path.display()))
+                })?;
+                match serde_json::from_str(&content) {
+                    Ok(zones) => zones,
+                    Err(e) => {
+                        warn!("ignore corrupted state file {}: {}", path.display(), e);
+                        HashMap::new()
+                    }
+                }
+            }
+            _ => HashMap::new(),
+        };
+
+        Ok(Self {
+            path,
+            force_refresh,
+            zones: Mutex::new(zones),
+        })
+    }
+
+    pub fn is_fresh(&self, key: &str, current: &ZoneState) -> bool {
+        let zones = self.zones.lock().expect("state lock poisoned");
+        let Some(last) = zones.get(key) else {
+            return false;
+        };
+
+        if last.config_hash != current.config_hash || last.addrs != current.addrs {
+            return false;
+        }
+
+        // Syncs start a little later than scheduled, e.g. after the ip
+        // lookups, so a zone checked about every `force_refresh` seconds
+        // would otherwise only be refreshed at every other check.
+        let slack = self.force_refresh / 10;
+        now().saturating_sub(last.synced_at) + slack < self.force_refresh
+    }
+
+    // `current` is the state computed before the sync.
+    pub fn record_success(&self, key: &str, current: ZoneState) {
+        let mut zones = self.zones.lock().expect("state lock poisoned");
+        zones.insert(key.to_owned(), current);
+        self.save(&zones);
+    }
+
+    pub fn record_failure(&self, key: &str) {
+        let mut zones = self.zones.lock().expect("state lock poisoned");
+        if zones.remove(key).is_some() {
+            self.save(&zones);
+        }
+    }
+
+    // A state file that cannot be written only costs extra API calls, so it
+    // is not treated as a sync failure.
+    fn save(&self, zones: &HashMap<String, ZoneState>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let result = serde_json::to_string_pretty(zones)
+            .map_err(AppErr::from)
+            .and_then(|content| {
+                let tmp = path.with_extension("tmp");
+                std::fs::write(&tmp, content)?;
+                std::fs::rename(&tmp, path)?;
+                Ok(())
+            });
+
+        match result {
+            Ok(_) => debug!("state saved to {}", path.display()),
+            Err(e) => warn!("cannot save state to {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// 64-bit FNV-1a, which unlike the std hasher is fixed. The hash is only as
+// stable as the serialization it is given.
+fn fingerprint(data: &str) -> String {
+    let hash = data.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn synced_ago(cache: &StateCache, secs: u64) -> ZoneState {
+        let mut state = ZoneState::new(BTreeMap::new(), "{}");
+        state.synced_at = now() - secs;
+        cache.record_success("zone", state);
+        ZoneState::new(BTreeMap::new(), "{}")
+    }
+
+    #[test]
+    fn refresh_is_due_at_a_check_interval_of_force_refresh() {
+        let cache = StateCache::load(None, 3600).unwrap();
+
+        // A tick of an hourly schedule, a little less than an hour after the
+        // previous sync started.
+        let current = synced_ago(&cache, 3599);
+        assert!(!cache.is_fresh("zone", &current));
+        let current = synced_ago(&cache, 3300);
+        assert!(!cache.is_fresh("zone", &current));
+
+        let current = synced_ago(&cache, 1800);
+        assert!(cache.is_fresh("zone", &current));
+    }
+
+    #[test]
+    fn changes_are_never_fresh() {
+        let cache = StateCache::load(None, 3600).unwrap();
+        synced_ago(&cache, 10);
+
+        let config = ZoneState::new(BTreeMap::new(), "{\"records\":[]}");
+        assert!(!cache.is_fresh("zone", &config));
+
+        let addrs = BTreeMap::from([("default/IPv4".to_owned(), "192.0.2.1".to_owned())]);
+        assert!(!cache.is_fresh("zone", &ZoneState::new(addrs, "{}")));
+
+        cache.record_failure("zone");
+        assert!(!cache.is_fresh("zone", &ZoneState::new(BTreeMap::new(), "{}")));
+    }
+}
@@ -1,12 +1,16 @@
-use crate::backends::{Backend, Cloudflare};
+use crate::backends::{Backend, Cloudflare, DNSSync, Zones};
 use crate::err::*;
+use crate::get_ip::{IpDiscovery, IpSources};
+use crate::netlink::NetlinkWatch;
+use crate::state::StateCache;
 
 use serde::Deserialize;
 use serde_yaml::Value as YamlValue;
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Deserialize)]
 struct ConfigBackend {
@@ -15,15 +19,44 @@ struct ConfigBackend {
     object: YamlValue,
 }
 
+fn default_force_refresh() -> u64 {
+    3600
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
 #[derive(Debug, Deserialize)]
 struct ConfigYaml {
+    // The default for backends and zones which set none, 0 syncs once.
     check_interval: u64,
+    // Where the result of the last sync of each zone is kept across restarts.
+    #[serde(default)]
+    state_file: Option<PathBuf>,
+    // Seconds after which remote records are listed again, even if nothing
+    // changed locally since the last successful sync.
+    #[serde(default = "default_force_refresh")]
+    force_refresh: u64,
+    // How many zones are synced at the same time, across all backends.
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    #[serde(default)]
+    netlink_watch: Option<NetlinkWatch>,
+    #[serde(default)]
+    ip_discovery: IpDiscovery,
+    #[serde(default)]
+    ip_sources: HashMap<String, IpDiscovery>,
     backends: Vec<ConfigBackend>,
 }
 
 #[derive(Debug)]
 pub struct Config {
     pub check_interval: u64,
+    pub concurrency: usize,
+    pub netlink_watch: Option<NetlinkWatch>,
+    pub ip_sources: IpSources,
+    pub state: StateCache,
 }
 
 impl ConfigYaml {
@@ -31,17 +64,42 @@ impl ConfigYaml {
     where
         P: AsRef<Path>,
     {
-        let file = File::open(file).expect("Open config file failed");
+        let path = file.as_ref();
+        let file = File::open(path).map_err(|e| AppErr::Config {
+            msg: format!("open config file {}", path.display()),
+            source: Some(Box::new(e)),
+        })?;
         let file_reader = BufReader::new(file);
 
         let rval = serde_yaml::from_reader(file_reader)?;
         Ok(rval)
     }
 
-    pub fn new_config(&self) -> Config {
-        Config {
-            check_interval: self.check_interval,
+    pub fn new_config(&mut self) -> Result<Config> {
+        if self.concurrency == 0 {
+            return Err(AppErr::config("concurrency must be at least 1".to_string()));
         }
+        // A check_interval of 0 syncs once and exits, nothing would watch.
+        if self.check_interval == 0 && self.netlink_watch.is_some() {
+            return Err(AppErr::config(
+                "netlink_watch needs a global check_interval, 0 runs once".to_string(),
+            ));
+        }
+
+        let ip_sources = IpSources::new(
+            std::mem::take(&mut self.ip_discovery),
+            std::mem::take(&mut self.ip_sources),
+        )?;
+
+        let state = StateCache::load(self.state_file.take(), self.force_refresh)?;
+
+        Ok(Config {
+            check_interval: self.check_interval,
+            concurrency: self.concurrency,
+            netlink_watch: self.netlink_watch.take(),
+            ip_sources,
+            state,
+        })
     }
 
     pub fn create_backends(self) -> Result<Vec<Backend>> {
@@ -54,15 +112,28 @@ impl ConfigYaml {
                     Backend::Cloudflare(cloudlare)
                 }
                 _ => {
-                    return Err(AppErr {
-                        msg: format!("unknown backend {}", backend_yaml.provider),
-                    })
+                    return Err(AppErr::config(format!(
+                        "unknown backend {}",
+                        backend_yaml.provider
+                    )))
                 }
             };
 
             backends.push(backend);
         }
 
+        // A global check_interval of 0 syncs once and exits, the intervals of
+        // backends and zones would be ignored.
+        let overridden = backends
+            .iter()
+            .any(|backend| backend.check_intervals().iter().any(Option::is_some));
+        if self.check_interval == 0 && overridden {
+            return Err(AppErr::config(
+                "check_interval of a backend or zone needs a global check_interval, 0 runs once"
+                    .to_string(),
+            ));
+        }
+
         Ok(backends)
     }
 }
@@ -71,10 +142,28 @@ pub async fn app_init<P>(config_file: P) -> Result<(Config, Vec<Backend>)>
 where
     P: AsRef<Path>,
 {
-    let conf_yaml = ConfigYaml::from_yaml(config_file)?;
+    let mut conf_yaml = ConfigYaml::from_yaml(config_file)?;
+
+    let config = conf_yaml.new_config()?;
+    let mut backends = conf_yaml.create_backends()?;
 
-    let config = conf_yaml.new_config();
-    let backends = conf_yaml.create_backends()?;
+    for backend in backends.iter() {
+        for (source, family) in backend.wanted_ips(Zones::All).into_iter() {
+            if !config.ip_sources.contains(&source) {
+                return Err(AppErr::config(format!("unknown ip source '{}'", source)));
+            }
+            if !config.ip_sources.provides(&source, family) {
+                return Err(AppErr::config(format!(
+                    "ip source '{}' has no {} source configured",
+                    source, family
+                )));
+            }
+        }
+    }
+
+    for backend in backends.iter_mut() {
+        backend.setup().await?;
+    }
 
     Ok((config, backends))
 }